@@ -1,151 +1,2369 @@
+//! Shproto packet framing, encoding and decoding for `no_std` targets.
+//!
+//! No function in this crate panics on any input: malformed, truncated or
+//! oversized data is reported through `ShprotoError`, and buffers too small
+//! for a packet header are rejected at compile time. The one exception is
+//! the chained `ShprotoPacket::push()` fixture helper, which asserts in
+//! debug builds.
+
 #![no_std]
 
-use heapless;
+#[cfg(feature = "std")]
+extern crate std;
+
+#[cfg(feature = "alloc")]
+extern crate alloc;
+
+use core::marker::PhantomData;
+
+#[cfg(all(feature = "crc-modbus", feature = "crc-ccitt"))]
+compile_error!("features `crc-modbus` and `crc-ccitt` are mutually exclusive");
+
+/// Advances a running protocol CRC by one byte, CRC-16/MODBUS unless the
+/// `crc-ccitt` feature selects CCITT instead. Start from
+/// `CrcParams::PROTOCOL.init`.
+pub fn crc16_update(crc: u16, byte: u8) -> u16 {
+    #[cfg(feature = "crc-table")]
+    {
+        crc16_table(crc, byte)
+    }
+    #[cfg(not(feature = "crc-table"))]
+    {
+        crc16_bitwise(crc, byte, CrcParams::PROTOCOL.poly)
+    }
+}
 
+/// Same as `crc16_update()`.
 pub fn crc16(crc: u16, byte: u8) -> u16 {
+    crc16_update(crc, byte)
+}
+
+/// Protocol CRC of a whole slice, starting from `0xFFFF`.
+pub fn crc16_slice(bytes: &[u8]) -> u16 {
+    let mut crc = Crc16::new();
+    crc.update_slice(bytes);
+    crc.finalize()
+}
+
+const fn crc16_bitwise(crc: u16, byte: u8, poly: u16) -> u16 {
     let mut crc = crc ^ (byte as u16);
-    for _ in 0..8 {
+    let mut i = 0;
+    while i < 8 {
         if (crc & 0x0001) != 0 {
-            crc = (crc >> 1) ^ 0xA001
+            crc = (crc >> 1) ^ poly
         } else {
-            crc = crc >> 1
+            crc >>= 1
         }
+        i += 1;
     }
     crc
 }
 
-#[derive(Debug, Copy, Clone)]
+#[cfg(feature = "crc-table")]
+const fn make_crc16_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = crc16_bitwise(0, i as u8, CrcParams::PROTOCOL.poly);
+        i += 1;
+    }
+    table
+}
+
+#[cfg(feature = "crc-table")]
+static CRC16_TABLE: [u16; 256] = make_crc16_table();
+
+/// Table-driven equivalent of `crc16()`, one lookup per byte.
+#[cfg(feature = "crc-table")]
+pub fn crc16_table(crc: u16, byte: u8) -> u16 {
+    (crc >> 8) ^ CRC16_TABLE[((crc ^ byte as u16) & 0xFF) as usize]
+}
+
+/// CRC-16 parameters for the reflected (LSB-first) algorithm used by the
+/// protocol. `poly` is given in reflected form.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CrcParams {
+    pub poly: u16,
+    pub init: u16,
+}
+
+impl CrcParams {
+    /// CRC-16/MODBUS, the protocol default.
+    pub const MODBUS: CrcParams = CrcParams {
+        poly: 0xA001,
+        init: 0xFFFF,
+    };
+    /// CCITT polynomial 0x1021 in reflected form (CRC-16/MCRF4XX).
+    pub const CCITT: CrcParams = CrcParams {
+        poly: 0x8408,
+        init: 0xFFFF,
+    };
+    /// The variant chosen at build time, used wherever no parameters are
+    /// given: CCITT with the `crc-ccitt` feature, MODBUS otherwise. The
+    /// default `crc-modbus` feature selects nothing by itself; it only makes
+    /// enabling `crc-ccitt` without `default-features = false` a build error.
+    #[cfg(not(feature = "crc-ccitt"))]
+    pub const PROTOCOL: CrcParams = CrcParams::MODBUS;
+    #[cfg(feature = "crc-ccitt")]
+    pub const PROTOCOL: CrcParams = CrcParams::CCITT;
+
+    /// Advances `crc` by one byte.
+    pub fn update(&self, crc: u16, byte: u8) -> u16 {
+        if self.poly == CrcParams::PROTOCOL.poly {
+            crc16_update(crc, byte)
+        } else {
+            crc16_bitwise(crc, byte, self.poly)
+        }
+    }
+}
+
+impl Default for CrcParams {
+    fn default() -> Self {
+        CrcParams::PROTOCOL
+    }
+}
+
+/// Incremental CRC, also the running CRC of packets, encoders and parsers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Crc16 {
+    value: u16,
+    params: CrcParams,
+}
+
+impl Crc16 {
+    /// Accumulator for the protocol CRC, `CrcParams::PROTOCOL`.
+    pub const fn new() -> Self {
+        Self::with_params(CrcParams::PROTOCOL)
+    }
+
+    pub const fn with_params(params: CrcParams) -> Self {
+        Crc16 {
+            value: params.init,
+            params,
+        }
+    }
+
+    // back to `params.init`, keeping the parameters
+    fn reset(&mut self) {
+        self.value = self.params.init;
+    }
+
+    pub fn update(&mut self, byte: u8) {
+        self.value = self.params.update(self.value, byte);
+    }
+
+    pub fn update_slice(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.update(*byte);
+        }
+    }
+
+    pub fn finalize(self) -> u16 {
+        self.value
+    }
+}
+
+impl Default for Crc16 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
 pub enum ShprotoError {
-    PushFailed
+    /// Kept for compatibility. Capacity errors are now reported as
+    /// `BufferOverflow` or `FrameTooLong`.
+    PushFailed,
+    /// The packet buffer `N` is too small for the frame.
+    BufferOverflow,
+    /// A received frame did not fit in the parser's packet buffer.
+    FrameTooLong,
+    /// A strict parser received a frame whose CRC did not check out.
+    CrcMismatch,
+    /// The underlying serial reader reported an error.
+    Io,
+    /// A frame had a bad header, footer or escape sequence.
+    MalformedFrame,
+    /// An `ESCAPE` byte was followed by a byte that does not unescape to a
+    /// control byte.
+    InvalidEscape,
+    /// A fragment arrived out of order, or one before it was missing.
+    UnexpectedFragment,
+    /// A byte was added to a packet after `complete()`; `clear()` it first.
+    AlreadyComplete,
+    /// `start()` was called after payload bytes were already added.
+    AlreadyStarted,
+}
+
+impl core::fmt::Display for ShprotoError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let msg = match self {
+            ShprotoError::PushFailed => "shproto push failed",
+            ShprotoError::BufferOverflow => "shproto buffer overflow",
+            ShprotoError::FrameTooLong => "shproto frame too long",
+            ShprotoError::CrcMismatch => "shproto CRC mismatch",
+            ShprotoError::Io => "shproto I/O error",
+            ShprotoError::MalformedFrame => "shproto malformed frame",
+            ShprotoError::InvalidEscape => "shproto invalid escape sequence",
+            ShprotoError::UnexpectedFragment => "shproto unexpected fragment",
+            ShprotoError::AlreadyComplete => "shproto packet already complete",
+            ShprotoError::AlreadyStarted => "shproto packet already started",
+        };
+        f.write_str(msg)
+    }
 }
 
+#[cfg(feature = "std")]
+impl std::error::Error for ShprotoError {}
+
 pub enum ControlByte {}
 impl ControlByte {
     pub const START: u8 = 0xFE;
     pub const ESCAPE: u8 = 0xFD;
     pub const STOP: u8 = 0xA5;
+    /// Idle byte sent before `START` and between frames. It is optional on
+    /// receive and is not counted as dropped.
+    pub const FILLER: u8 = 0xFF;
+}
+
+/// Command bytes reserved for `ShprotoPacket::ack()` and `nack()`, taken
+/// from the ASCII ACK and NAK codes. An ACK carries `[ACK, seq]`, a NACK
+/// carries `[NACK, seq, reason]`.
+pub enum ReservedCommand {}
+impl ReservedCommand {
+    pub const ACK: u8 = 0x06;
+    pub const NACK: u8 = 0x15;
+}
+
+/// Destination addresses for `ShprotoPacket::with_address()` on a
+/// multi-drop bus. Frames sent to `BROADCAST` are for every device.
+pub enum Address {}
+impl Address {
+    pub const BROADCAST: u8 = 0xFF;
+}
+
+/// A command that can start a packet, typically a user-defined enum with
+/// explicit discriminants. Plain `u8` commands implement it too.
+pub trait Command {
+    fn byte(&self) -> u8;
+}
+
+impl Command for u8 {
+    fn byte(&self) -> u8 {
+        *self
+    }
+}
+
+/// Sentinel bytes delimiting a frame. A control byte inside the frame is
+/// sent as `ESCAPE` followed by its bitwise complement, so the complement of
+/// a control byte must not be a control byte itself.
+pub trait Framing {
+    const START: u8;
+    const ESCAPE: u8;
+    const STOP: u8;
+
+    fn is_control(byte: u8) -> bool {
+        byte == Self::START || byte == Self::ESCAPE || byte == Self::STOP
+    }
+}
+
+/// The standard shproto sentinels from `ControlByte`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct DefaultFraming;
+
+impl Framing for DefaultFraming {
+    const START: u8 = ControlByte::START;
+    const ESCAPE: u8 = ControlByte::ESCAPE;
+    const STOP: u8 = ControlByte::STOP;
+}
+
+// storage shared by the heapless and alloc packet types
+trait FrameBuffer {
+    fn push_byte(&mut self, byte: u8) -> Result<(), ShprotoError>;
+}
+
+impl<const N: usize> FrameBuffer for heapless::Vec<u8, N> {
+    fn push_byte(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        self.push(byte).map_err(|_| ShprotoError::BufferOverflow)
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl FrameBuffer for alloc::vec::Vec<u8> {
+    fn push_byte(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        self.push(byte);
+        Ok(())
+    }
+}
+
+// push `byte` to a frame, escaping control bytes
+fn push_escaped<F: Framing>(data: &mut impl FrameBuffer, byte: u8) -> Result<(), ShprotoError> {
+    if F::is_control(byte) {
+        data.push_byte(F::ESCAPE)?;
+        data.push_byte(!byte)
+    } else {
+        data.push_byte(byte)
+    }
+}
+
+// validate a framed buffer and feed its unescaped body to `add_byte`
+fn unframe<F: Framing>(
+    bytes: &[u8],
+    mut add_byte: impl FnMut(u8) -> Result<(), ShprotoError>,
+) -> Result<(), ShprotoError> {
+    let bytes = bytes.strip_prefix(&[ControlByte::FILLER]).unwrap_or(bytes);
+    let body = match bytes {
+        [start, body @ .., stop] if *start == F::START && *stop == F::STOP => body,
+        _ => return Err(ShprotoError::MalformedFrame),
+    };
+    let mut escaped = false;
+    for byte in body {
+        match (escaped, *byte) {
+            (false, byte) if byte == F::ESCAPE => escaped = true,
+            (false, byte) if F::is_control(byte) => return Err(ShprotoError::MalformedFrame),
+            (false, byte) => add_byte(byte)?,
+            (true, byte) if F::is_control(!byte) => {
+                add_byte(!byte)?;
+                escaped = false;
+            }
+            (true, _) => return Err(ShprotoError::InvalidEscape),
+        }
+    }
+    if escaped {
+        return Err(ShprotoError::MalformedFrame);
+    }
+    Ok(())
 }
 
-#[derive(Debug)]
-pub struct ShprotoPacket<const N: usize = 256> {
+/// Framing bytes around an unescaped payload: `0xFF START`, two CRC bytes
+/// and `STOP`.
+pub const FRAME_OVERHEAD: usize = 5;
+
+/// Buffer size `N` that fits any frame with up to `max_payload` payload
+/// bytes (command and arguments), even when every payload and CRC byte is
+/// escaped, e.g. `ShprotoPacket::<{ required_buffer(32) }>::new()`.
+///
+/// This is `2 * max_payload + 7`, two more than the `2 * max_payload + 5`
+/// that only covers an escaped payload, because both CRC bytes can be
+/// control bytes too.
+pub const fn required_buffer(max_payload: usize) -> usize {
+    max_payload * 2 + FRAME_OVERHEAD + 2
+}
+
+/// `ShprotoPacket` sized by its maximum payload rather than its buffer, see
+/// `required_buffer()`:
+///
+/// ```
+/// let packet = <shproto_rs::shproto_packet!(4)>::from_payload(0xFE, &[0xFD, 0xA5, 0xFE]).unwrap();
+/// assert!(packet.is_valid());
+/// ```
+#[macro_export]
+macro_rules! shproto_packet {
+    ($max_payload:expr) => {
+        $crate::ShprotoPacket<{ $crate::required_buffer($max_payload) }>
+    };
+}
+
+/// A shproto frame under construction or decoded by `ShprotoParser`.
+///
+/// `N` is the capacity of the framed buffer and must be at least 6 (header,
+/// command, CRC and stop byte); smaller buffers fail to compile:
+///
+/// ```compile_fail
+/// let packet = shproto_rs::ShprotoPacket::<4>::new();
+/// ```
+pub struct ShprotoPacket<const N: usize = 256, F: Framing = DefaultFraming> {
     pub data: heapless::Vec<u8, N>,
-    crc: u16,
+    // unescaped command, arguments and (once completed) CRC bytes
+    body: heapless::Vec<u8, N>,
+    crc: Crc16,
+    // false for frames that end at STOP without CRC bytes
+    has_crc: bool,
+    // the first payload byte is an address, see `with_address()`
+    addressed: bool,
+    // the byte after the command is a sequence number, see `with_sequence()`
+    sequenced: bool,
     completed: bool,
     valid: bool,
+    // bytes exactly as received by a parser, START through STOP
+    #[cfg(feature = "capture-raw")]
+    raw: heapless::Vec<u8, N>,
+    // tick at which parse_byte_at() received STOP
+    #[cfg(feature = "timestamp")]
+    timestamp: Option<u32>,
+    framing: PhantomData<F>,
 }
-impl<const N: usize> ShprotoPacket<N> {
+impl<const N: usize, F: Framing> ShprotoPacket<N, F> {
     pub fn new() -> Self {
+        Self::with_crc_params(CrcParams::PROTOCOL)
+    }
+
+    pub fn with_crc_params(crc_params: CrcParams) -> Self {
+        const { assert!(N >= 6, "ShprotoPacket buffer N must be at least 6") };
         let mut p = ShprotoPacket {
             data: Default::default(),
-            crc: 0xFFFF,
+            body: Default::default(),
+            crc: Crc16::with_params(crc_params),
+            has_crc: true,
+            addressed: false,
+            sequenced: false,
             completed: false,
-            valid: false
+            valid: false,
+            #[cfg(feature = "capture-raw")]
+            raw: Default::default(),
+            #[cfg(feature = "timestamp")]
+            timestamp: None,
+            framing: PhantomData,
         };
-        p.data.push(0xFF).unwrap();
-        p.data.push(0xFE).unwrap();
+        // cannot fail, N >= 6 is checked above
+        let _ = p.data.extend_from_slice(&[ControlByte::FILLER, F::START]);
         p
     }
 
+    /// Packet for links that already guarantee integrity: `complete()`
+    /// appends no CRC bytes and the frame ends at `STOP`. Decode such frames
+    /// with a parser configured by `ShprotoParser::set_crc(false)`.
+    pub fn without_crc() -> Self {
+        let mut packet = Self::new();
+        packet.has_crc = false;
+        packet
+    }
+
+    /// Packet without the `0xFF 0xFE` header, usable in `const` context.
+    /// The header is written before the first byte added, or by `complete()`.
+    pub const fn empty() -> Self {
+        const { assert!(N >= 6, "ShprotoPacket buffer N must be at least 6") };
+        ShprotoPacket {
+            data: heapless::Vec::new(),
+            body: heapless::Vec::new(),
+            crc: Crc16::new(),
+            has_crc: true,
+            addressed: false,
+            sequenced: false,
+            completed: false,
+            valid: false,
+            #[cfg(feature = "capture-raw")]
+            raw: heapless::Vec::new(),
+            #[cfg(feature = "timestamp")]
+            timestamp: None,
+            framing: PhantomData,
+        }
+    }
+
+    /// Builds a completed packet carrying `command` followed by `args`.
+    pub fn from_payload(command: u8, args: &[u8]) -> Result<Self, ShprotoError> {
+        let mut packet = Self::new();
+        packet.start(command)?;
+        packet.add_bytes(args)?;
+        packet.complete()?;
+        Ok(packet)
+    }
+
+    /// Like `from_payload()`, taking a typed command.
+    pub fn from_command<C: Command>(command: C, args: &[u8]) -> Result<Self, ShprotoError> {
+        Self::from_payload(command.byte(), args)
+    }
+
+    /// Completed packet with a sequence byte between `command` and `args`,
+    /// covered by the CRC. Read it back with `sequence()`; a parser only
+    /// reads it after `ShprotoParser::set_sequenced(true)`.
+    pub fn with_sequence(command: u8, seq: u8, args: &[u8]) -> Result<Self, ShprotoError> {
+        let mut packet = Self::new();
+        packet.sequenced = true;
+        packet.start(command)?;
+        packet.add_byte(seq)?;
+        packet.add_bytes(args)?;
+        packet.complete()?;
+        Ok(packet)
+    }
+
+    /// Response to `request` echoing its command byte, and its address if it
+    /// has one, followed by `args`. Fails with `MalformedFrame` if the
+    /// request has no command.
+    pub fn respond_to(request: &Self, args: &[u8]) -> Result<Self, ShprotoError> {
+        let command = request.command().ok_or(ShprotoError::MalformedFrame)?;
+        match request.address() {
+            Some(addr) => Self::with_address(addr, command, args),
+            None => Self::from_payload(command, args),
+        }
+    }
+
+    /// Completed packet with a destination address byte before `command`,
+    /// covered by the CRC. Read it back with `address()` and `is_for()`; a
+    /// parser only reads it after `ShprotoParser::set_addressed(true)`.
+    pub fn with_address(addr: u8, command: u8, args: &[u8]) -> Result<Self, ShprotoError> {
+        let mut packet = Self::new();
+        packet.addressed = true;
+        packet.start(addr)?;
+        packet.add_byte(command)?;
+        packet.add_bytes(args)?;
+        packet.complete()?;
+        Ok(packet)
+    }
+
+    /// Keepalive packet carrying only `command`, with no arguments.
+    pub fn heartbeat(command: u8) -> Result<Self, ShprotoError> {
+        Self::from_payload(command, &[])
+    }
+
+    /// Acknowledges the packet with sequence number `seq`.
+    pub fn ack(seq: u8) -> Result<Self, ShprotoError> {
+        Self::from_payload(ReservedCommand::ACK, &[seq])
+    }
+
+    /// Rejects the packet with sequence number `seq` for an
+    /// application-defined `reason`.
+    pub fn nack(seq: u8, reason: u8) -> Result<Self, ShprotoError> {
+        Self::from_payload(ReservedCommand::NACK, &[seq, reason])
+    }
+
+    /// Wraps an already framed buffer (optional `0xFF` filler, `0xFE`,
+    /// escaped body, `0xA5`), unescaping the body and recomputing the CRC.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ShprotoError> {
+        let mut packet = Self::new();
+        unframe::<F>(bytes, |byte| packet.add_byte(byte))?;
+        packet
+            .data
+            .push(F::STOP)
+            .map_err(|_| ShprotoError::BufferOverflow)?;
+        packet.completed = true;
+        packet.valid = packet.crc.finalize() == 0;
+        Ok(packet)
+    }
+
+    /// Adds the command byte, which must be the first payload byte.
     pub fn start(&mut self, command: u8) -> Result<(), ShprotoError> {
+        if self.completed {
+            return Err(ShprotoError::AlreadyComplete);
+        }
+        if !self.body.is_empty() {
+            return Err(ShprotoError::AlreadyStarted);
+        }
         self.add_byte(command)
     }
 
-    pub fn add_byte(&mut self, byte: u8) -> Result<(), ShprotoError>{
+    // writes the header of a packet built from `empty()` on its first byte
+    fn ensure_header(&mut self) -> Result<(), ShprotoError> {
+        if self.data.is_empty() {
+            self.data
+                .extend_from_slice(&[ControlByte::FILLER, F::START])
+                .map_err(|_| ShprotoError::BufferOverflow)?;
+        }
+        Ok(())
+    }
+
+    pub fn add_byte(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        if self.completed {
+            return Err(ShprotoError::AlreadyComplete);
+        }
+        self.ensure_header()?;
+        // check room for an escaped pair up front, never leaving a lone ESCAPE
+        let needed = if F::is_control(byte) { 2 } else { 1 };
+        if self.data.capacity() - self.data.len() < needed {
+            return Err(ShprotoError::BufferOverflow);
+        }
         // calculate crc
-        self.crc = crc16(self.crc, byte);
+        if self.has_crc {
+            self.crc.update(byte);
+        }
         // push byte
-        let need_escape: bool = match byte {
-            ControlByte::START | ControlByte::ESCAPE | ControlByte::STOP => true,
-            _ => false
+        push_escaped::<F>(&mut self.data, byte)?;
+        self.body
+            .push(byte)
+            .map_err(|_| ShprotoError::BufferOverflow)?;
+        Ok(())
+    }
+
+    /// Drops the payload, any address and sequence byte so the buffer can be
+    /// rebuilt with `start()`, keeping the header and the CRC parameters.
+    pub fn clear(&mut self) {
+        self.data.truncate(2);
+        self.body.clear();
+        self.crc.reset();
+        self.addressed = false;
+        self.sequenced = false;
+        self.completed = false;
+        self.valid = false;
+        #[cfg(feature = "capture-raw")]
+        self.raw.clear();
+        #[cfg(feature = "timestamp")]
+        {
+            self.timestamp = None;
+        }
+    }
+
+    /// Framed wire bytes: header, escaped payload, CRC and `STOP`. Before
+    /// `complete()` this is the partial frame built so far.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Iterator over the bytes returned by `as_bytes()`.
+    pub fn iter(&self) -> impl Iterator<Item = u8> + '_ {
+        self.data.iter().copied()
+    }
+
+    /// Consumes the packet and returns the framed buffer. It only holds a
+    /// complete frame if `complete()` was called.
+    pub fn into_inner(self) -> heapless::Vec<u8, N> {
+        self.data
+    }
+
+    /// Framed bytes as space separated hex, e.g. `FF FE 03 ...`.
+    pub fn to_hex<const M: usize>(&self) -> Result<heapless::String<M>, ShprotoError> {
+        use core::fmt::Write;
+        let mut hex = heapless::String::new();
+        for (i, byte) in self.data.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            write!(hex, "{}{:02X}", sep, byte).map_err(|_| ShprotoError::BufferOverflow)?;
+        }
+        Ok(hex)
+    }
+
+    /// Logical payload: the command byte followed by its arguments, without
+    /// framing, escapes, CRC or the address byte of an addressed packet.
+    pub fn payload(&self) -> &[u8] {
+        let end = if self.completed && self.has_crc {
+            self.body.len().saturating_sub(2)
+        } else {
+            self.body.len()
+        };
+        let start = usize::from(self.addressed).min(end);
+        &self.body[start..end]
+    }
+
+    /// The two CRC bytes stored in the frame before `STOP`, unescaped and
+    /// little-endian. `None` until the packet is completed, and for packets
+    /// without CRC.
+    pub fn crc_bytes(&self) -> Option<[u8; 2]> {
+        match (self.completed && self.has_crc, self.body.len()) {
+            (true, len) if len >= 2 => Some([self.body[len - 2], self.body[len - 1]]),
+            _ => None,
+        }
+    }
+
+    // CRC carried by the frame once completed, running CRC otherwise
+    fn frame_crc(&self) -> u16 {
+        self.crc_bytes()
+            .map_or(self.crc.finalize(), u16::from_le_bytes)
+    }
+
+    /// Bytes exactly as received by the parser, from `START` through `STOP`.
+    /// Empty for packets that were not decoded by a `ShprotoParser`.
+    #[cfg(feature = "capture-raw")]
+    pub fn raw_bytes(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Copies `payload()` into a vec of capacity `M` that outlives the
+    /// packet.
+    pub fn payload_owned<const M: usize>(&self) -> Result<heapless::Vec<u8, M>, ShprotoError> {
+        heapless::Vec::from_slice(self.payload()).map_err(|_| ShprotoError::BufferOverflow)
+    }
+
+    /// The `now_ms` tick passed to `ShprotoParser::parse_byte_at()` with the
+    /// frame's `STOP` byte. `None` for packets completed any other way.
+    #[cfg(feature = "timestamp")]
+    pub fn timestamp(&self) -> Option<u32> {
+        self.timestamp
+    }
+
+    /// The bytes of `payload()`, one at a time.
+    pub fn payload_iter(&self) -> impl Iterator<Item = u8> + '_ {
+        self.payload().iter().copied()
+    }
+
+    /// Wire length of a completed frame, including header, escapes, CRC and
+    /// `STOP`. `None` until completed.
+    pub fn frame_len(&self) -> Option<usize> {
+        self.completed.then_some(self.data.len())
+    }
+
+    /// Free slots left in the framed buffer. A plain byte takes one slot, a
+    /// control byte two once escaped; `complete()` needs up to five more
+    /// for the CRC and `STOP`.
+    pub fn remaining(&self) -> usize {
+        self.data.capacity() - self.data.len()
+    }
+
+    /// Number of logical payload bytes, see `payload()`.
+    pub fn len(&self) -> usize {
+        self.payload().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether the CRC checked out. Only meaningful once `is_complete()`.
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed
+    }
+
+    /// Unescapes the framed bytes in `data`, recomputes the CRC over the
+    /// payload and compares it with the two CRC bytes before `STOP`. Always
+    /// false for an incomplete packet, one without CRC or a frame that no
+    /// longer unframes.
+    pub fn verify_crc(&self) -> bool {
+        if !self.completed || !self.has_crc {
+            return false;
+        }
+        // the CRC runs two bytes behind, so `tail` ends up holding the CRC bytes
+        let mut crc = Crc16::with_params(self.crc.params);
+        let mut tail = [0u8; 2];
+        let mut len = 0;
+        let framed = unframe::<F>(&self.data, |byte| {
+            if len >= 2 {
+                crc.update(tail[0]);
+            }
+            tail = [tail[1], byte];
+            len += 1;
+            Ok(())
+        });
+        framed.is_ok() && len >= 2 && crc.finalize().to_le_bytes() == tail
+    }
+
+    /// First payload byte: the byte passed to `start()`, or the one after
+    /// the address of an addressed packet.
+    pub fn command(&self) -> Option<u8> {
+        self.payload().first().copied()
+    }
+
+    /// Command byte and the argument bytes after it, not counting the
+    /// sequence byte of a sequenced packet.
+    pub fn split(&self) -> Option<(u8, &[u8])> {
+        let (command, rest) = self.payload().split_first()?;
+        let args = match rest {
+            [_, args @ ..] if self.sequenced => args,
+            _ => rest,
         };
-        if need_escape {
-            self.data.push(ControlByte::ESCAPE)
-                .map_err(|_| ShprotoError::PushFailed)?;
-            self.data.push((!byte) & 0xFF)
-                .map_err(|_| ShprotoError::PushFailed)?;
+        Some((*command, args))
+    }
+
+    /// Little-endian `u16` at `offset` into the arguments, i.e. counted
+    /// from the byte after the command, or after the sequence byte of a
+    /// sequenced packet. `None` if the payload is too short.
+    pub fn payload_u16_le(&self, offset: usize) -> Option<u16> {
+        let (_, args) = self.split()?;
+        let bytes = args.get(offset..offset.checked_add(2)?)?;
+        Some(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    /// Little-endian `u32` at `offset` into the arguments, see
+    /// `payload_u16_le()`.
+    pub fn payload_u32_le(&self, offset: usize) -> Option<u32> {
+        let (_, args) = self.split()?;
+        let bytes = args.get(offset..offset.checked_add(4)?)?;
+        Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Sequence byte of a packet built by `with_sequence()` or parsed with
+    /// `ShprotoParser::set_sequenced(true)`. `None` for other packets.
+    pub fn sequence(&self) -> Option<u8> {
+        if self.sequenced {
+            self.payload().get(1).copied()
+        } else {
+            None
+        }
+    }
+
+    /// Address byte of a packet built by `with_address()` or parsed with
+    /// `ShprotoParser::set_addressed(true)`. `None` for other packets.
+    pub fn address(&self) -> Option<u8> {
+        if self.addressed {
+            self.body.first().copied()
         } else {
-            self.data.push(byte)
-                .map_err(|_| ShprotoError::PushFailed)?;
+            None
+        }
+    }
+
+    /// Whether an addressed packet is meant for `my_addr`, either directly
+    /// or through `Address::BROADCAST`.
+    pub fn is_for(&self, my_addr: u8) -> bool {
+        matches!(self.address(), Some(addr) if addr == my_addr || addr == Address::BROADCAST)
+    }
+
+    /// Maps a valid packet's command and arguments to a typed message with
+    /// `decode`. `None` if the packet is not valid or `decode` rejects it.
+    pub fn decode_as<T, D: Fn(u8, &[u8]) -> Option<T>>(&self, decode: D) -> Option<T> {
+        if !self.valid {
+            return None;
+        }
+        self.split()
+            .and_then(|(command, args)| decode(command, args))
+    }
+
+    /// Whether `split()` gives `command` and `args`. The CRC is neither
+    /// compared nor recomputed.
+    pub fn payload_eq(&self, command: u8, args: &[u8]) -> bool {
+        self.split() == Some((command, args))
+    }
+
+    /// Whether this packet carries `command`, e.g. a device echoing the
+    /// request's command in its response.
+    pub fn matches_command(&self, command: u8) -> bool {
+        self.command() == Some(command)
+    }
+
+    /// Whether this is a valid ACK as built by `ack()`.
+    pub fn is_ack(&self) -> bool {
+        self.valid && self.matches_command(ReservedCommand::ACK) && self.len() == 2
+    }
+
+    /// Whether this is a valid NACK as built by `nack()`.
+    pub fn is_nack(&self) -> bool {
+        self.valid && self.matches_command(ReservedCommand::NACK) && self.len() == 3
+    }
+
+    /// Appends `bytes` one by one like `add_byte()`. On error the packet
+    /// keeps the bytes added before the failure and should be discarded.
+    pub fn add_bytes(&mut self, bytes: &[u8]) -> Result<(), ShprotoError> {
+        for byte in bytes {
+            self.add_byte(*byte)?;
         }
         Ok(())
     }
 
-    pub fn complete(&mut self) {
-        // get CRC bytes
-        for byte in self.crc.to_le_bytes().iter() {
-            self.add_byte(*byte).unwrap()
+    /// Chainable `add_byte()` for fixtures and payloads known to fit, e.g.
+    /// `ShprotoPacket::<16>::new().push(0x03).push(0x99)`.
+    ///
+    /// # Panics
+    ///
+    /// In debug builds, if `add_byte()` fails. Release builds ignore the
+    /// error and return the packet unchanged.
+    pub fn push(mut self, byte: u8) -> Self {
+        let result = self.add_byte(byte);
+        debug_assert!(result.is_ok(), "ShprotoPacket::push: {:?}", result);
+        self
+    }
+
+    /// Appends `v` in little-endian byte order, like the CRC.
+    pub fn add_u16_le(&mut self, v: u16) -> Result<(), ShprotoError> {
+        self.add_bytes(&v.to_le_bytes())
+    }
+
+    /// Appends `v` in little-endian byte order, like the CRC.
+    pub fn add_u32_le(&mut self, v: u32) -> Result<(), ShprotoError> {
+        self.add_bytes(&v.to_le_bytes())
+    }
+
+    pub fn complete(&mut self) -> Result<(), ShprotoError> {
+        if self.completed {
+            return Err(ShprotoError::AlreadyComplete);
+        }
+        self.ensure_header()?;
+        // check room for the escaped CRC and STOP up front, so a failed call
+        // leaves the packet untouched and can be retried
+        let crc_bytes = if self.has_crc {
+            &self.crc.finalize().to_le_bytes()[..]
+        } else {
+            &[]
+        };
+        let needed = 1 + crc_bytes
+            .iter()
+            .map(|b| if F::is_control(*b) { 2 } else { 1 })
+            .sum::<usize>();
+        if self.data.capacity() - self.data.len() < needed {
+            return Err(ShprotoError::BufferOverflow);
+        }
+        for byte in crc_bytes {
+            self.add_byte(*byte)?;
         }
-        self.data.push(ControlByte::STOP).unwrap();
+        self.data
+            .push(F::STOP)
+            .map_err(|_| ShprotoError::BufferOverflow)?;
         self.completed = true;
-        if self.crc == 0 {
+        if self.crc.finalize() == 0 || !self.has_crc {
             self.valid = true;
         }
+        Ok(())
     }
-}
 
-enum ShprotoParserState {
-    Start,
-    Data,
-    EscapedData,
+    /// Completes the packet and returns the framed bytes. Idempotent: on an
+    /// already completed packet it returns the frame unchanged.
+    pub fn finish(&mut self) -> Result<&[u8], ShprotoError> {
+        if !self.completed {
+            self.complete()?;
+        }
+        Ok(self.as_bytes())
+    }
 }
 
-pub struct ShprotoParser<const N: usize> {
-    state: ShprotoParserState,
-    packet: ShprotoPacket<N>,
+impl<const N: usize, F: Framing> Default for ShprotoPacket<N, F> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
-impl<const N: usize> ShprotoParser<N> {
-    pub fn new() -> Self {
-        ShprotoParser {
-            state: ShprotoParserState::Start,
-            packet: ShprotoPacket::new(),
+// not derived so that framing markers need not be Clone
+impl<const N: usize, F: Framing> Clone for ShprotoPacket<N, F> {
+    fn clone(&self) -> Self {
+        ShprotoPacket {
+            data: self.data.clone(),
+            body: self.body.clone(),
+            crc: self.crc,
+            has_crc: self.has_crc,
+            addressed: self.addressed,
+            sequenced: self.sequenced,
+            completed: self.completed,
+            valid: self.valid,
+            #[cfg(feature = "capture-raw")]
+            raw: self.raw.clone(),
+            #[cfg(feature = "timestamp")]
+            timestamp: self.timestamp,
+            framing: PhantomData,
         }
     }
+}
 
-    pub fn parse_byte(&mut self, byte: u8) -> Result<Option<ShprotoPacket<N>>, ShprotoError> {
-        match self.state {
-            ShprotoParserState::Start => {
-                if byte == ControlByte::START {
-                    self.packet = ShprotoPacket::new();
-                    self.state = ShprotoParserState::Data;
-                }
-            }
-            ShprotoParserState::Data => {
-                match byte {
-                    ControlByte::START => {
-                        self.packet = ShprotoPacket::new();
-                        self.state = ShprotoParserState::Data;
-                    }
-                    ControlByte::ESCAPE => {
-                        self.state = ShprotoParserState::EscapedData;
-                    }
-                    ControlByte::STOP => {
-                        // Create a new packet to return.
-                        let completed_packet = ShprotoPacket {
-                            data: self.packet.data.clone(),
-                            crc: self.packet.crc,
-                            completed: true,
-                            valid: self.packet.crc == 0,
-                        };
-
-                        // Reset the parser state and return the completed packet.
-                        self.state = ShprotoParserState::Start;
-                        self.packet = ShprotoPacket::new();
-                        return Ok(Some(completed_packet));
-                    }
-                    _ => {
-                        self.packet.add_byte(byte)?;
-                    }
-                }
-            }
-            ShprotoParserState::EscapedData => {
-                let unescaped_byte = (!byte) & 0xFF;
-                self.packet.add_byte(unescaped_byte)?;
-                self.state = ShprotoParserState::Data;
-            }
+impl<const N: usize, F: Framing> core::fmt::Debug for ShprotoPacket<N, F> {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let mut s = f.debug_struct("ShprotoPacket");
+        if let Some(addr) = self.address() {
+            s.field("address", &format_args!("{:02X}", addr));
         }
+        if let Some(seq) = self.sequence() {
+            s.field("sequence", &format_args!("{:02X}", seq));
+        }
+        s.field("command", &format_args!("{:02X?}", self.command()))
+            .field("payload", &format_args!("{:02X?}", self.payload()))
+            .field("crc", &format_args!("{:04X}", self.frame_crc()))
+            .field("valid", &self.valid)
+            .field("completed", &self.completed)
+            .finish()
+    }
+}
 
-        Ok(None)
+/// Packets compare equal when their address, sequence, unescaped payload
+/// and CRC match. The
+/// framed bytes in `data` and the `completed`/`valid` flags are not
+/// compared, so a built and a decoded packet carrying the same payload are
+/// equal regardless of how the bytes were escaped on the wire.
+impl<const N: usize, F: Framing> PartialEq for ShprotoPacket<N, F> {
+    fn eq(&self, other: &Self) -> bool {
+        self.address() == other.address()
+            && self.sequence() == other.sequence()
+            && self.payload() == other.payload()
+            && self.frame_crc() == other.frame_crc()
+    }
+}
+
+impl<const N: usize, F: Framing> Eq for ShprotoPacket<N, F> {}
+
+/// Compares `payload()`, i.e. command and arguments without framing,
+/// escapes or CRC.
+impl<const N: usize, F: Framing> PartialEq<[u8]> for ShprotoPacket<N, F> {
+    fn eq(&self, other: &[u8]) -> bool {
+        self.payload() == other
+    }
+}
+
+/// Compares `payload()`, see `PartialEq<[u8]>`.
+impl<const N: usize, F: Framing> PartialEq<&[u8]> for ShprotoPacket<N, F> {
+    fn eq(&self, other: &&[u8]) -> bool {
+        self.payload() == *other
+    }
+}
+
+/// Hashes the address, sequence, unescaped payload and CRC, consistent
+/// with `PartialEq`. The
+/// header and framing bytes are not hashed.
+impl<const N: usize, F: Framing> core::hash::Hash for ShprotoPacket<N, F> {
+    fn hash<H: core::hash::Hasher>(&self, state: &mut H) {
+        self.address().hash(state);
+        self.sequence().hash(state);
+        self.payload().hash(state);
+        self.frame_crc().hash(state);
+    }
+}
+
+/// Same as the `core::hash::Hash` impl, for heapless' `IndexMap`/`IndexSet`.
+impl<const N: usize, F: Framing> hash32::Hash for ShprotoPacket<N, F> {
+    fn hash<H: hash32::Hasher>(&self, state: &mut H) {
+        state.write(self.address().as_slice());
+        state.write(self.sequence().as_slice());
+        state.write(self.payload());
+        state.write(&self.frame_crc().to_le_bytes());
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl<const N: usize, F: Framing> defmt::Format for ShprotoPacket<N, F> {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "ShprotoPacket {{ payload: {=[u8]:02X}, crc: {=u16:04X}, valid: {=bool} }}",
+            self.payload(),
+            self.frame_crc(),
+            self.valid,
+        )
+    }
+}
+
+// serialized form of a packet: the logical payload rather than the framed bytes
+#[cfg(feature = "serde")]
+#[derive(serde::Serialize)]
+struct PacketRef<'a> {
+    command: Option<u8>,
+    payload: &'a [u8],
+    crc: u16,
+    valid: bool,
+    completed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    address: Option<u8>,
+    #[serde(skip_serializing_if = "core::ops::Not::not")]
+    sequenced: bool,
+}
+
+#[cfg(feature = "serde")]
+#[derive(serde::Deserialize)]
+struct PacketRepr<const N: usize> {
+    payload: heapless::Vec<u8, N>,
+    crc: u16,
+    valid: bool,
+    completed: bool,
+    #[serde(default)]
+    address: Option<u8>,
+    #[serde(default)]
+    sequenced: bool,
+}
+
+#[cfg(feature = "serde")]
+impl<const N: usize, F: Framing> serde::Serialize for ShprotoPacket<N, F> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PacketRef {
+            command: self.command(),
+            payload: self.payload(),
+            crc: self.frame_crc(),
+            valid: self.valid,
+            completed: self.completed,
+            address: self.address(),
+            sequenced: self.sequenced,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// Rebuilds the framed `data` from the payload and CRC. A bad CRC is kept
+/// as is, so a captured corrupt frame round-trips unchanged.
+#[cfg(feature = "serde")]
+impl<'de, const N: usize, F: Framing> serde::Deserialize<'de> for ShprotoPacket<N, F> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error;
+        let repr = PacketRepr::<N>::deserialize(deserializer)?;
+        let mut packet = Self::new();
+        if let Some(addr) = repr.address {
+            packet.addressed = true;
+            packet.add_byte(addr).map_err(D::Error::custom)?;
+        }
+        packet.sequenced = repr.sequenced;
+        packet.add_bytes(&repr.payload).map_err(D::Error::custom)?;
+        if repr.completed {
+            packet
+                .add_bytes(&repr.crc.to_le_bytes())
+                .map_err(D::Error::custom)?;
+            packet
+                .data
+                .push(F::STOP)
+                .map_err(|_| D::Error::custom(ShprotoError::BufferOverflow))?;
+            packet.completed = true;
+        } else {
+            packet.crc.value = repr.crc;
+        }
+        packet.valid = repr.valid;
+        Ok(packet)
+    }
+}
+
+/// Heap-backed counterpart of `ShprotoPacket` for host-side tools, whose
+/// buffers grow as needed so `add_byte()` never overflows.
+#[cfg(feature = "alloc")]
+#[derive(Debug, Clone)]
+pub struct ShprotoPacketVec {
+    pub data: alloc::vec::Vec<u8>,
+    body: alloc::vec::Vec<u8>,
+    crc: Crc16,
+    completed: bool,
+    valid: bool,
+}
+
+#[cfg(feature = "alloc")]
+impl ShprotoPacketVec {
+    pub fn new() -> Self {
+        Self::with_crc_params(CrcParams::PROTOCOL)
+    }
+
+    pub fn with_crc_params(crc_params: CrcParams) -> Self {
+        ShprotoPacketVec {
+            data: alloc::vec![ControlByte::FILLER, ControlByte::START],
+            body: alloc::vec::Vec::new(),
+            crc: Crc16::with_params(crc_params),
+            completed: false,
+            valid: false,
+        }
+    }
+
+    /// See `ShprotoPacket::from_bytes()`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ShprotoError> {
+        let mut packet = Self::new();
+        unframe::<DefaultFraming>(bytes, |byte| packet.add_byte(byte))?;
+        packet.data.push(ControlByte::STOP);
+        packet.completed = true;
+        packet.valid = packet.crc.finalize() == 0;
+        Ok(packet)
+    }
+
+    /// See `ShprotoPacket::start()`.
+    pub fn start(&mut self, command: u8) -> Result<(), ShprotoError> {
+        if self.completed {
+            return Err(ShprotoError::AlreadyComplete);
+        }
+        if !self.body.is_empty() {
+            return Err(ShprotoError::AlreadyStarted);
+        }
+        self.add_byte(command)
+    }
+
+    /// See `ShprotoPacket::add_byte()`. Never overflows, but still fails
+    /// with `AlreadyComplete` after `complete()`.
+    pub fn add_byte(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        if self.completed {
+            return Err(ShprotoError::AlreadyComplete);
+        }
+        self.crc.update(byte);
+        // pushing to an alloc Vec cannot fail
+        let _ = push_escaped::<DefaultFraming>(&mut self.data, byte);
+        self.body.push(byte);
+        Ok(())
+    }
+
+    pub fn add_bytes(&mut self, bytes: &[u8]) -> Result<(), ShprotoError> {
+        for byte in bytes {
+            self.add_byte(*byte)?;
+        }
+        Ok(())
+    }
+
+    pub fn complete(&mut self) -> Result<(), ShprotoError> {
+        for byte in self.crc.finalize().to_le_bytes() {
+            self.add_byte(byte)?;
+        }
+        self.data.push(ControlByte::STOP);
+        self.completed = true;
+        self.valid = self.crc.finalize() == 0;
+        Ok(())
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// See `ShprotoPacket::payload()`.
+    pub fn payload(&self) -> &[u8] {
+        let end = if self.completed {
+            self.body.len().saturating_sub(2)
+        } else {
+            self.body.len()
+        };
+        &self.body[..end]
+    }
+
+    pub fn command(&self) -> Option<u8> {
+        self.payload().first().copied()
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed
+    }
+
+    // see `ShprotoPacket::frame_crc()`
+    fn frame_crc(&self) -> u16 {
+        match (self.completed, self.body.len()) {
+            (true, len) if len >= 2 => u16::from_le_bytes([self.body[len - 2], self.body[len - 1]]),
+            _ => self.crc.finalize(),
+        }
+    }
+}
+
+/// Compares payload and CRC, like `ShprotoPacket`.
+#[cfg(feature = "alloc")]
+impl PartialEq for ShprotoPacketVec {
+    fn eq(&self, other: &Self) -> bool {
+        self.payload() == other.payload() && self.frame_crc() == other.frame_crc()
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl Eq for ShprotoPacketVec {}
+
+#[cfg(feature = "alloc")]
+impl Default for ShprotoPacketVec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Fluent packet construction. Push failures are remembered and reported
+/// by `build()`, e.g. `PacketBuilder::<256>::new().command(0x03).arg(0x99).build()?`.
+pub struct PacketBuilder<const N: usize, F: Framing = DefaultFraming> {
+    packet: ShprotoPacket<N, F>,
+    error: Option<ShprotoError>,
+}
+
+impl<const N: usize, F: Framing> PacketBuilder<N, F> {
+    pub fn new() -> Self {
+        PacketBuilder {
+            packet: ShprotoPacket::new(),
+            error: None,
+        }
+    }
+
+    fn apply(
+        mut self,
+        f: impl FnOnce(&mut ShprotoPacket<N, F>) -> Result<(), ShprotoError>,
+    ) -> Self {
+        if self.error.is_none() {
+            self.error = f(&mut self.packet).err();
+        }
+        self
+    }
+
+    pub fn command(self, command: u8) -> Self {
+        self.apply(|p| p.start(command))
+    }
+
+    pub fn arg(self, byte: u8) -> Self {
+        self.apply(|p| p.add_byte(byte))
+    }
+
+    pub fn args(self, bytes: &[u8]) -> Self {
+        self.apply(|p| p.add_bytes(bytes))
+    }
+
+    pub fn build(self) -> Result<ShprotoPacket<N, F>, ShprotoError> {
+        let mut packet = self.packet;
+        if let Some(e) = self.error {
+            return Err(e);
+        }
+        packet.complete()?;
+        Ok(packet)
+    }
+}
+
+impl<const N: usize, F: Framing> Default for PacketBuilder<N, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Produces the framed wire bytes of a packet one at a time, without
+/// buffering the frame.
+pub struct Encoder<'a, F: Framing = DefaultFraming> {
+    command: u8,
+    args: &'a [u8],
+    // position in header, command, args, CRC low, CRC high, STOP
+    pos: usize,
+    crc: Crc16,
+    pending: Option<u8>,
+    framing: PhantomData<F>,
+}
+
+impl<'a> Encoder<'a> {
+    pub fn new(command: u8, args: &'a [u8]) -> Self {
+        Self::with_crc_params(command, args, CrcParams::PROTOCOL)
+    }
+
+    pub fn with_crc_params(command: u8, args: &'a [u8], crc_params: CrcParams) -> Self {
+        Self::with_framing(command, args, crc_params)
+    }
+}
+
+impl<'a, F: Framing> Encoder<'a, F> {
+    /// Encoder for alternate sentinels, e.g. `Encoder::<MyFraming>::with_framing(...)`.
+    pub fn with_framing(command: u8, args: &'a [u8], crc_params: CrcParams) -> Self {
+        Encoder {
+            command,
+            args,
+            pos: 0,
+            crc: Crc16::with_params(crc_params),
+            pending: None,
+            framing: PhantomData,
+        }
+    }
+}
+
+impl<F: Framing> Iterator for Encoder<'_, F> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        if let Some(byte) = self.pending.take() {
+            return Some(byte);
+        }
+        let args_end = 3 + self.args.len();
+        let pos = self.pos;
+        let byte = match pos {
+            0 => ControlByte::FILLER,
+            1 => F::START,
+            2 => self.command,
+            p if p < args_end => self.args[p - 3],
+            p if p == args_end => self.crc.finalize().to_le_bytes()[0],
+            p if p == args_end + 1 => self.crc.finalize().to_le_bytes()[1],
+            p if p == args_end + 2 => F::STOP,
+            _ => return None,
+        };
+        self.pos += 1;
+        if pos < 2 || pos == args_end + 2 {
+            return Some(byte);
+        }
+        if pos < args_end {
+            self.crc.update(byte);
+        }
+        if F::is_control(byte) {
+            self.pending = Some(!byte);
+            Some(F::ESCAPE)
+        } else {
+            Some(byte)
+        }
+    }
+}
+
+/// Exact wire length of the frame for `command` and `args`, counting the
+/// escapes needed in the payload and in the CRC, without building it.
+pub fn encoded_len(command: u8, args: &[u8]) -> usize {
+    Encoder::new(command, args).count()
+}
+
+/// Writes the framed packet for `command` and `args` into `out`, e.g. a DMA
+/// buffer, and returns the number of bytes written.
+pub fn encode_into(command: u8, args: &[u8], out: &mut [u8]) -> Result<usize, ShprotoError> {
+    let mut len = 0;
+    for byte in Encoder::new(command, args) {
+        *out.get_mut(len).ok_or(ShprotoError::BufferOverflow)? = byte;
+        len += 1;
+    }
+    Ok(len)
+}
+
+enum ShprotoParserState {
+    Start,
+    Data,
+    EscapedData,
+}
+
+/// Public view of the parser state machine, as returned by `ShprotoParser::state()`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ParserPhase {
+    /// Waiting for a `START` byte.
+    Start,
+    /// Receiving payload bytes. The two CRC bytes arrive in this phase
+    /// too, as the frame does not mark where the payload ends.
+    Data,
+    /// An `ESCAPE` byte was received and the next byte will be unescaped.
+    EscapedData,
+}
+
+/// Outcome of feeding one byte to `ShprotoParser::parse_byte_event()`.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseEvent<const N: usize, F: Framing = DefaultFraming> {
+    /// The byte was consumed, no frame completed.
+    None,
+    /// The byte arrived outside a frame and was discarded. Filler bytes
+    /// are not reported.
+    Dropped(u8),
+    /// The byte completed a frame.
+    Packet(ShprotoPacket<N, F>),
+}
+
+/// Link-health counters kept by `ShprotoParser`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ParserStats {
+    /// Bytes discarded while waiting for a `START`, not counting
+    /// `ControlByte::FILLER`.
+    pub bytes_dropped: u32,
+    /// Frames completed with a valid CRC.
+    pub frames_decoded: u32,
+    /// Frames completed with a bad CRC.
+    pub crc_errors: u32,
+    /// `STOP` bytes seen outside a frame, a sign the stream is out of sync.
+    /// They are also counted in `bytes_dropped`.
+    pub orphan_stops: u32,
+}
+
+pub struct ShprotoParser<const N: usize, F: Framing = DefaultFraming> {
+    state: ShprotoParserState,
+    packet: ShprotoPacket<N, F>,
+    // fresh accumulator each new packet starts from
+    crc: Crc16,
+    strict: bool,
+    report_aborted: bool,
+    has_crc: bool,
+    addressed: bool,
+    sequenced: bool,
+    // wire and unescaped bytes of the current frame, between START and STOP
+    raw_count: usize,
+    logical_count: usize,
+    timeout_ms: Option<u32>,
+    last_byte_ms: u32,
+    stats: ParserStats,
+    #[cfg(feature = "async")]
+    rx: RxBuffer,
+}
+
+// bytes read ahead by read_packet_async() that belong to the next frame
+#[cfg(feature = "async")]
+#[derive(Default)]
+struct RxBuffer {
+    buf: [u8; 16],
+    pos: usize,
+    len: usize,
+}
+
+impl<const N: usize, F: Framing> ShprotoParser<N, F> {
+    pub fn new() -> Self {
+        Self::with_crc_params(CrcParams::PROTOCOL)
+    }
+
+    pub fn with_crc_params(crc_params: CrcParams) -> Self {
+        ShprotoParser {
+            state: ShprotoParserState::Start,
+            packet: ShprotoPacket::with_crc_params(crc_params),
+            crc: Crc16::with_params(crc_params),
+            strict: false,
+            report_aborted: false,
+            has_crc: true,
+            addressed: false,
+            sequenced: false,
+            raw_count: 0,
+            logical_count: 0,
+            timeout_ms: None,
+            last_byte_ms: 0,
+            stats: ParserStats::default(),
+            #[cfg(feature = "async")]
+            rx: RxBuffer::default(),
+        }
+    }
+
+    fn new_packet(&self) -> ShprotoPacket<N, F> {
+        let mut packet = ShprotoPacket::with_crc_params(self.crc.params);
+        packet.has_crc = self.has_crc;
+        packet.addressed = self.addressed;
+        packet.sequenced = self.sequenced;
+        packet
+    }
+
+    /// In strict mode a frame with a bad CRC is reported as
+    /// `CrcMismatch` instead of being returned with `is_valid() == false`.
+    /// A frame too short to hold a CRC, or cut short by a new `START`, is
+    /// reported as `MalformedFrame`.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// When set, a frame cut short by a new `START` is returned as a packet
+    /// with `is_complete() == false` and `is_valid() == false` instead of
+    /// being dropped silently.
+    pub fn set_report_aborted(&mut self, report_aborted: bool) {
+        self.report_aborted = report_aborted;
+    }
+
+    /// With `false`, frames carry no CRC bytes, as built by
+    /// `ShprotoPacket::without_crc()`, and every completed frame is valid.
+    /// Takes effect from the next frame.
+    pub fn set_crc(&mut self, enabled: bool) {
+        self.has_crc = enabled;
+    }
+
+    /// With `true`, the first payload byte of each frame is read as an
+    /// address, as built by `ShprotoPacket::with_address()`, and `command()`
+    /// skips it. Takes effect from the next frame.
+    pub fn set_addressed(&mut self, addressed: bool) {
+        self.addressed = addressed;
+    }
+
+    /// With `true`, the byte after each frame's command is read as a
+    /// sequence number, as built by `ShprotoPacket::with_sequence()`, and
+    /// `split()` leaves it out of the arguments. Takes effect from the next
+    /// frame.
+    pub fn set_sequenced(&mut self, sequenced: bool) {
+        self.sequenced = sequenced;
+    }
+
+    /// Maximum gap between two bytes of a frame for `parse_byte_at()`.
+    /// `None` disables the timeout.
+    pub fn set_timeout(&mut self, timeout_ms: Option<u32>) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Drops any partially received frame and waits for the next `START`.
+    pub fn reset(&mut self) {
+        self.state = ShprotoParserState::Start;
+        self.packet = self.new_packet();
+        self.raw_count = 0;
+        self.logical_count = 0;
+    }
+
+    /// Wire bytes received for the current frame between `START` and
+    /// `STOP`, escapes included. After `STOP` this is the completed frame's
+    /// count until the next `START`.
+    pub fn raw_byte_count(&self) -> usize {
+        self.raw_count
+    }
+
+    /// Unescaped bytes, payload and CRC, that `raw_byte_count()` decoded to.
+    pub fn logical_byte_count(&self) -> usize {
+        self.logical_count
+    }
+
+    // a START arrived mid-frame: begin a new frame, handing back the old one
+    fn restart(&mut self) -> Result<ParseEvent<N, F>, ShprotoError> {
+        let fresh_packet = self.new_packet();
+        let aborted_packet = core::mem::replace(&mut self.packet, fresh_packet);
+        #[cfg(feature = "capture-raw")]
+        let _ = self.packet.raw.push(F::START);
+        self.state = ShprotoParserState::Data;
+        self.raw_count = 0;
+        self.logical_count = 0;
+        if self.strict {
+            Err(ShprotoError::MalformedFrame)
+        } else if self.report_aborted {
+            Ok(ParseEvent::Packet(aborted_packet))
+        } else {
+            Ok(ParseEvent::None)
+        }
+    }
+
+    // add a received byte, dropping the frame if it outgrows the buffer
+    fn push_byte(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        if self.packet.add_byte(byte).is_err() {
+            self.reset();
+            return Err(ShprotoError::FrameTooLong);
+        }
+        self.logical_count += 1;
+        Ok(())
+    }
+
+    /// The partially received packet, or `None` while waiting for `START`.
+    /// Its CRC bytes are counted as payload until the frame completes.
+    pub fn in_progress(&self) -> Option<&ShprotoPacket<N, F>> {
+        match self.state {
+            ShprotoParserState::Start => None,
+            _ => Some(&self.packet),
+        }
+    }
+
+    /// Takes the partially received packet, if any, and resets the parser.
+    /// The packet is neither complete nor valid.
+    pub fn take_partial(&mut self) -> Option<ShprotoPacket<N, F>> {
+        let fresh_packet = self.new_packet();
+        let packet = core::mem::replace(&mut self.packet, fresh_packet);
+        let partial = match self.state {
+            ShprotoParserState::Start => None,
+            _ => Some(packet),
+        };
+        self.state = ShprotoParserState::Start;
+        self.raw_count = 0;
+        self.logical_count = 0;
+        partial
+    }
+
+    pub fn stats(&self) -> ParserStats {
+        self.stats
+    }
+
+    pub fn state(&self) -> ParserPhase {
+        match self.state {
+            ShprotoParserState::Start => ParserPhase::Start,
+            ShprotoParserState::Data => ParserPhase::Data,
+            ShprotoParserState::EscapedData => ParserPhase::EscapedData,
+        }
+    }
+
+    pub fn parse_byte(&mut self, byte: u8) -> Result<Option<ShprotoPacket<N, F>>, ShprotoError> {
+        match self.parse_byte_event(byte)? {
+            ParseEvent::Packet(packet) => Ok(Some(packet)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Like `parse_byte()`, but also reports bytes discarded outside a frame.
+    pub fn parse_byte_event(&mut self, byte: u8) -> Result<ParseEvent<N, F>, ShprotoError> {
+        // START is captured into the fresh packet it begins
+        #[cfg(feature = "capture-raw")]
+        if byte != F::START && !matches!(self.state, ShprotoParserState::Start) {
+            let _ = self.packet.raw.push(byte);
+        }
+        if byte != F::START && byte != F::STOP && !matches!(self.state, ShprotoParserState::Start) {
+            self.raw_count += 1;
+        }
+        match self.state {
+            ShprotoParserState::Start => {
+                if byte == F::START {
+                    self.reset();
+                    #[cfg(feature = "capture-raw")]
+                    let _ = self.packet.raw.push(byte);
+                    self.state = ShprotoParserState::Data;
+                } else if byte != ControlByte::FILLER {
+                    if byte == F::STOP {
+                        self.stats.orphan_stops = self.stats.orphan_stops.wrapping_add(1);
+                    }
+                    self.stats.bytes_dropped = self.stats.bytes_dropped.wrapping_add(1);
+                    return Ok(ParseEvent::Dropped(byte));
+                }
+            }
+            ShprotoParserState::Data => {
+                match byte {
+                    b if b == F::START => {
+                        return self.restart();
+                    }
+                    b if b == F::ESCAPE => {
+                        self.state = ShprotoParserState::EscapedData;
+                    }
+                    b if b == F::STOP => {
+                        // Reset the parser state and return the completed packet.
+                        self.state = ShprotoParserState::Start;
+                        let fresh_packet = self.new_packet();
+                        let mut completed_packet =
+                            core::mem::replace(&mut self.packet, fresh_packet);
+                        completed_packet
+                            .data
+                            .push(F::STOP)
+                            .map_err(|_| ShprotoError::FrameTooLong)?;
+                        // too short to hold a CRC, so not a CRC error
+                        if self.strict
+                            && completed_packet.has_crc
+                            && completed_packet.body.len() < 2
+                        {
+                            return Err(ShprotoError::MalformedFrame);
+                        }
+                        completed_packet.completed = true;
+                        completed_packet.valid =
+                            completed_packet.crc.finalize() == 0 || !completed_packet.has_crc;
+                        if completed_packet.valid {
+                            self.stats.frames_decoded = self.stats.frames_decoded.wrapping_add(1);
+                        } else {
+                            self.stats.crc_errors = self.stats.crc_errors.wrapping_add(1);
+                        }
+                        if self.strict && !completed_packet.valid {
+                            return Err(ShprotoError::CrcMismatch);
+                        }
+                        return Ok(ParseEvent::Packet(completed_packet));
+                    }
+                    _ => {
+                        self.push_byte(byte)?;
+                    }
+                }
+            }
+            ShprotoParserState::EscapedData => {
+                if byte == F::START {
+                    // a raw START is never escaped, the line resynced mid-frame
+                    return self.restart();
+                } else {
+                    let unescaped_byte = !byte;
+                    if !F::is_control(unescaped_byte) {
+                        self.reset();
+                        return Err(ShprotoError::InvalidEscape);
+                    }
+                    self.push_byte(unescaped_byte)?;
+                    self.state = ShprotoParserState::Data;
+                }
+            }
+        }
+
+        Ok(ParseEvent::None)
+    }
+
+    /// Like `parse_byte()`, but first drops a partially received frame if
+    /// more than the configured timeout elapsed since the previous byte.
+    /// `now_ms` is a free-running millisecond tick and may wrap. With the
+    /// `timestamp` feature a completed packet records it, see
+    /// `ShprotoPacket::timestamp()`.
+    pub fn parse_byte_at(
+        &mut self,
+        byte: u8,
+        now_ms: u32,
+    ) -> Result<Option<ShprotoPacket<N, F>>, ShprotoError> {
+        if let Some(timeout_ms) = self.timeout_ms {
+            if now_ms.wrapping_sub(self.last_byte_ms) > timeout_ms {
+                self.reset();
+            }
+        }
+        self.last_byte_ms = now_ms;
+        #[cfg(feature = "timestamp")]
+        {
+            self.parse_byte(byte).map(|packet| {
+                packet.map(|mut packet| {
+                    packet.timestamp = packet.completed.then_some(now_ms);
+                    packet
+                })
+            })
+        }
+        #[cfg(not(feature = "timestamp"))]
+        {
+            self.parse_byte(byte)
+        }
+    }
+
+    /// Runs every byte of `bytes` through `parse_byte()` and pushes completed
+    /// packets into `out`. A frame split across calls is still decoded.
+    pub fn parse_slice<const M: usize>(
+        &mut self,
+        bytes: &[u8],
+        out: &mut heapless::Vec<ShprotoPacket<N, F>, M>,
+    ) -> Result<(), ShprotoError> {
+        for byte in bytes {
+            if let Some(packet) = self.parse_byte(*byte)? {
+                out.push(packet).map_err(|_| ShprotoError::BufferOverflow)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Consumes bytes from the front of `buf` until a packet completes,
+    /// leaving the unconsumed rest in `buf`. On error, `buf` starts after
+    /// the offending byte.
+    pub fn parse_until_packet(
+        &mut self,
+        buf: &mut &[u8],
+    ) -> Result<Option<ShprotoPacket<N, F>>, ShprotoError> {
+        while let Some((byte, rest)) = buf.split_first() {
+            *buf = rest;
+            if let Some(packet) = self.parse_byte(*byte)? {
+                return Ok(Some(packet));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Like `parse_slice()`, but hands each packet to `on_packet` instead
+    /// of collecting it. The packet is only borrowed for the call.
+    pub fn parse_slice_with<C: FnMut(&ShprotoPacket<N, F>)>(
+        &mut self,
+        bytes: &[u8],
+        mut on_packet: C,
+    ) -> Result<(), ShprotoError> {
+        for byte in bytes {
+            if let Some(packet) = self.parse_byte(*byte)? {
+                on_packet(&packet);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<const N: usize, F: Framing> ShprotoParser<N, F> {
+    /// Reads the bytes available from `reader` and returns a packet once one
+    /// is complete, `WouldBlock` until then. A serial error is reported as
+    /// `ShprotoError::Io`.
+    pub fn read_packet<R: embedded_hal::serial::Read<u8>>(
+        &mut self,
+        reader: &mut R,
+    ) -> nb::Result<ShprotoPacket<N, F>, ShprotoError> {
+        loop {
+            let byte = reader.read().map_err(|e| e.map(|_| ShprotoError::Io))?;
+            if let Some(packet) = self.parse_byte(byte).map_err(nb::Error::Other)? {
+                return Ok(packet);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "async")]
+impl<const N: usize, F: Framing> ShprotoParser<N, F> {
+    /// Awaits bytes from `reader` until a packet is complete. Bytes read
+    /// past the end of the packet are kept for the next call. A read error
+    /// or end of stream is reported as `ShprotoError::Io`.
+    pub async fn read_packet_async<R: embedded_io_async::Read>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<ShprotoPacket<N, F>, ShprotoError> {
+        loop {
+            while self.rx.pos < self.rx.len {
+                let byte = self.rx.buf[self.rx.pos];
+                self.rx.pos += 1;
+                if let Some(packet) = self.parse_byte(byte)? {
+                    return Ok(packet);
+                }
+            }
+            let len = reader
+                .read(&mut self.rx.buf)
+                .await
+                .map_err(|_| ShprotoError::Io)?;
+            if len == 0 {
+                return Err(ShprotoError::Io);
+            }
+            self.rx.pos = 0;
+            self.rx.len = len.min(self.rx.buf.len());
+        }
+    }
+}
+
+impl<const N: usize, F: Framing> Default for ShprotoParser<N, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What `ValidatingParser` keeps of a frame.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FrameSummary {
+    command: Option<u8>,
+    payload_len: usize,
+    valid: bool,
+}
+
+impl FrameSummary {
+    pub fn command(&self) -> Option<u8> {
+        self.command
+    }
+
+    /// Number of payload bytes, command included, CRC excluded.
+    pub fn payload_len(&self) -> usize {
+        self.payload_len
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+}
+
+/// Parser for receivers that only need each frame's command and CRC
+/// validity. It runs the CRC as bytes arrive and stores no payload, so it
+/// needs no buffer at all.
+pub struct ValidatingParser<F: Framing = DefaultFraming> {
+    state: ShprotoParserState,
+    crc: Crc16,
+    command: Option<u8>,
+    count: usize,
+    framing: PhantomData<F>,
+}
+
+impl<F: Framing> ValidatingParser<F> {
+    pub fn new() -> Self {
+        Self::with_crc_params(CrcParams::PROTOCOL)
+    }
+
+    pub fn with_crc_params(crc_params: CrcParams) -> Self {
+        ValidatingParser {
+            state: ShprotoParserState::Start,
+            crc: Crc16::with_params(crc_params),
+            command: None,
+            count: 0,
+            framing: PhantomData,
+        }
+    }
+
+    /// Drops any partially received frame and waits for the next `START`.
+    pub fn reset(&mut self) {
+        self.state = ShprotoParserState::Start;
+        self.crc.reset();
+        self.command = None;
+        self.count = 0;
+    }
+
+    fn add_byte(&mut self, byte: u8) {
+        self.crc.update(byte);
+        if self.count == 0 {
+            self.command = Some(byte);
+        }
+        self.count = self.count.saturating_add(1);
+    }
+
+    /// Like `ShprotoParser::parse_byte()`, returning only a summary of each
+    /// completed frame.
+    pub fn parse_byte(&mut self, byte: u8) -> Result<Option<FrameSummary>, ShprotoError> {
+        match self.state {
+            ShprotoParserState::Start => {
+                if byte == F::START {
+                    self.reset();
+                    self.state = ShprotoParserState::Data;
+                }
+            }
+            ShprotoParserState::Data => match byte {
+                b if b == F::START => {
+                    self.reset();
+                    self.state = ShprotoParserState::Data;
+                }
+                b if b == F::ESCAPE => self.state = ShprotoParserState::EscapedData,
+                b if b == F::STOP => {
+                    let summary = FrameSummary {
+                        command: self.command.filter(|_| self.count > 2),
+                        payload_len: self.count.saturating_sub(2),
+                        valid: self.count >= 2 && self.crc.finalize() == 0,
+                    };
+                    self.reset();
+                    return Ok(Some(summary));
+                }
+                _ => self.add_byte(byte),
+            },
+            ShprotoParserState::EscapedData => {
+                if byte == F::START {
+                    self.reset();
+                    self.state = ShprotoParserState::Data;
+                } else if F::is_control(!byte) {
+                    self.add_byte(!byte);
+                    self.state = ShprotoParserState::Data;
+                } else {
+                    self.reset();
+                    return Err(ShprotoError::InvalidEscape);
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<F: Framing> Default for ValidatingParser<F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes packets from any byte iterator, driving a `ShprotoParser`.
+pub struct StreamDecoder<I, const N: usize, F: Framing = DefaultFraming> {
+    bytes: I,
+    parser: ShprotoParser<N, F>,
+}
+
+impl<I: Iterator<Item = u8>, const N: usize, F: Framing> StreamDecoder<I, N, F> {
+    pub fn new(bytes: I) -> Self {
+        Self::with_parser(bytes, ShprotoParser::new())
+    }
+
+    /// Uses a preconfigured parser, e.g. a strict one or one with custom
+    /// CRC parameters.
+    pub fn with_parser(bytes: I, parser: ShprotoParser<N, F>) -> Self {
+        StreamDecoder { bytes, parser }
+    }
+
+    pub fn into_inner(self) -> (I, ShprotoParser<N, F>) {
+        (self.bytes, self.parser)
+    }
+}
+
+impl<I: Iterator<Item = u8>, const N: usize, F: Framing> Iterator for StreamDecoder<I, N, F> {
+    type Item = Result<ShprotoPacket<N, F>, ShprotoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        for byte in self.bytes.by_ref() {
+            match self.parser.parse_byte(byte) {
+                Ok(Some(packet)) => return Some(Ok(packet)),
+                Ok(None) => {}
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+/// Decodes a buffer like `packets()`, also yielding where each frame lies:
+/// `(start, end, result)` with `start` at the frame's `START` byte and
+/// `end` one past the `STOP` or the byte that caused the error.
+pub struct OffsetDecoder<'a, const N: usize, F: Framing = DefaultFraming> {
+    bytes: &'a [u8],
+    pos: usize,
+    frame_start: usize,
+    parser: ShprotoParser<N, F>,
+}
+
+impl<'a, const N: usize, F: Framing> OffsetDecoder<'a, N, F> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self::with_parser(bytes, ShprotoParser::new())
+    }
+
+    pub fn with_parser(bytes: &'a [u8], parser: ShprotoParser<N, F>) -> Self {
+        OffsetDecoder {
+            bytes,
+            pos: 0,
+            frame_start: 0,
+            parser,
+        }
+    }
+}
+
+impl<const N: usize, F: Framing> Iterator for OffsetDecoder<'_, N, F> {
+    type Item = (usize, usize, Result<ShprotoPacket<N, F>, ShprotoError>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(byte) = self.bytes.get(self.pos).copied() {
+            let pos = self.pos;
+            self.pos += 1;
+            let frame_start = self.frame_start;
+            if byte == F::START {
+                self.frame_start = pos;
+            }
+            let result = match self.parser.parse_byte(byte) {
+                Ok(None) => continue,
+                Ok(Some(packet)) => Ok(packet),
+                Err(e) => Err(e),
+            };
+            // a START only ever reports the frame it cut short, which ends
+            // just before it
+            if byte == F::START {
+                return Some((frame_start, pos, result));
+            }
+            return Some((self.frame_start, self.pos, result));
+        }
+        None
+    }
+}
+
+/// Decodes every frame in a complete buffer, see `StreamDecoder`.
+pub fn packets<const N: usize>(
+    bytes: &[u8],
+) -> impl Iterator<Item = Result<ShprotoPacket<N>, ShprotoError>> + '_ {
+    StreamDecoder::new(bytes.iter().copied())
+}
+
+/// Feeds bytes into a `ShprotoParser` and queues up to `Q` completed
+/// packets, so receiving and handling packets can happen at different times.
+pub struct BufferedDecoder<const N: usize, const Q: usize, F: Framing = DefaultFraming> {
+    parser: ShprotoParser<N, F>,
+    queue: heapless::Deque<ShprotoPacket<N, F>, Q>,
+}
+
+impl<const N: usize, const Q: usize, F: Framing> BufferedDecoder<N, Q, F> {
+    pub fn new() -> Self {
+        Self::with_parser(ShprotoParser::new())
+    }
+
+    pub fn with_parser(parser: ShprotoParser<N, F>) -> Self {
+        BufferedDecoder {
+            parser,
+            queue: heapless::Deque::new(),
+        }
+    }
+
+    /// Parses one byte. A packet completed while the queue is full is
+    /// dropped and reported as `BufferOverflow`.
+    pub fn push(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        if let Some(packet) = self.parser.parse_byte(byte)? {
+            self.queue
+                .push_back(packet)
+                .map_err(|_| ShprotoError::BufferOverflow)?;
+        }
+        Ok(())
+    }
+
+    /// Oldest queued packet.
+    pub fn pop(&mut self) -> Option<ShprotoPacket<N, F>> {
+        self.queue.pop_front()
+    }
+
+    /// Removes and yields the queued packets, oldest first.
+    pub fn drain(&mut self) -> impl Iterator<Item = ShprotoPacket<N, F>> + '_ {
+        core::iter::from_fn(move || self.queue.pop_front())
+    }
+}
+
+impl<const N: usize, const Q: usize, F: Framing> Default for BufferedDecoder<N, Q, F> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Splits a message too large for one packet into frames carrying
+/// `[command, index, total, chunk..]`, sized so every frame fits in `N`
+/// even when fully escaped. At most 255 fragments are supported.
+pub struct Fragmenter<'a, const N: usize, F: Framing = DefaultFraming> {
+    command: u8,
+    data: &'a [u8],
+    index: u8,
+    total: u8,
+    framing: PhantomData<F>,
+}
+
+impl<'a, const N: usize, F: Framing> Fragmenter<'a, N, F> {
+    // header, escaped command/index/total/chunk/CRC, STOP
+    const CHUNK_LEN: usize = (N.saturating_sub(7) / 2).saturating_sub(3);
+
+    pub fn new(command: u8, data: &'a [u8]) -> Result<Self, ShprotoError> {
+        if Self::CHUNK_LEN == 0 {
+            return Err(ShprotoError::BufferOverflow);
+        }
+        let total = data.len().div_ceil(Self::CHUNK_LEN).max(1);
+        let total = u8::try_from(total).map_err(|_| ShprotoError::BufferOverflow)?;
+        Ok(Fragmenter {
+            command,
+            data,
+            index: 0,
+            total,
+            framing: PhantomData,
+        })
+    }
+}
+
+impl<const N: usize, F: Framing> Iterator for Fragmenter<'_, N, F> {
+    type Item = Result<ShprotoPacket<N, F>, ShprotoError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index == self.total {
+            return None;
+        }
+        let start = self.index as usize * Self::CHUNK_LEN;
+        let end = self.data.len().min(start + Self::CHUNK_LEN);
+        let mut packet = ShprotoPacket::new();
+        let result = packet
+            .start(self.command)
+            .and_then(|_| packet.add_bytes(&[self.index, self.total]))
+            .and_then(|_| packet.add_bytes(&self.data[start..end]))
+            .and_then(|_| packet.complete());
+        self.index += 1;
+        Some(result.map(|_| packet))
+    }
+}
+
+/// Collects the frames produced by a `Fragmenter` into `buf`.
+pub struct Reassembler<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+    command: u8,
+    // index of the expected fragment, 0 while waiting for a first one
+    next: u8,
+    total: u8,
+}
+
+impl<'a> Reassembler<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Reassembler {
+            buf,
+            len: 0,
+            command: 0,
+            next: 0,
+            total: 0,
+        }
+    }
+
+    /// Adds a fragment and returns the whole message once the last one
+    /// arrives. Fragment 0 always starts a new message; any other fragment
+    /// out of sequence drops the partial message with `UnexpectedFragment`.
+    pub fn push<const N: usize, F: Framing>(
+        &mut self,
+        packet: &ShprotoPacket<N, F>,
+    ) -> Result<Option<&[u8]>, ShprotoError> {
+        let result = self.add_fragment(packet);
+        if !matches!(result, Ok(false)) {
+            self.next = 0;
+        }
+        match result? {
+            true => Ok(Some(&self.buf[..self.len])),
+            false => Ok(None),
+        }
+    }
+
+    fn add_fragment<const N: usize, F: Framing>(
+        &mut self,
+        packet: &ShprotoPacket<N, F>,
+    ) -> Result<bool, ShprotoError> {
+        if !packet.is_valid() {
+            return Err(ShprotoError::CrcMismatch);
+        }
+        let (command, index, total, chunk) = match packet.payload() {
+            [command, index, total, chunk @ ..] if index < total => {
+                (*command, *index, *total, chunk)
+            }
+            _ => return Err(ShprotoError::MalformedFrame),
+        };
+        if index == 0 {
+            self.len = 0;
+            self.command = command;
+            self.total = total;
+        } else if index != self.next || total != self.total || command != self.command {
+            return Err(ShprotoError::UnexpectedFragment);
+        }
+        let end = self.len + chunk.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(ShprotoError::BufferOverflow)?
+            .copy_from_slice(chunk);
+        self.len = end;
+        self.next = index + 1;
+        Ok(self.next == total)
+    }
+}
+
+/// Alternative frame without escaping: `0xFF START command len args CRC STOP`.
+/// The length byte counts the argument bytes, so at most 255 arguments fit
+/// and `N` must cover `len + 7`. The CRC covers command, length and args.
+pub struct LengthPrefixedPacket<const N: usize = 264> {
+    data: heapless::Vec<u8, N>,
+    completed: bool,
+    valid: bool,
+}
+
+impl<const N: usize> LengthPrefixedPacket<N> {
+    pub fn from_payload(command: u8, args: &[u8]) -> Result<Self, ShprotoError> {
+        let len = u8::try_from(args.len()).map_err(|_| ShprotoError::BufferOverflow)?;
+        let mut data = heapless::Vec::new();
+        data.extend_from_slice(&[ControlByte::FILLER, ControlByte::START, command, len])
+            .map_err(|_| ShprotoError::BufferOverflow)?;
+        data.extend_from_slice(args)
+            .map_err(|_| ShprotoError::BufferOverflow)?;
+        let mut crc = Crc16::new();
+        crc.update_slice(&data[2..]);
+        data.extend_from_slice(&crc.finalize().to_le_bytes())
+            .map_err(|_| ShprotoError::BufferOverflow)?;
+        data.push(ControlByte::STOP)
+            .map_err(|_| ShprotoError::BufferOverflow)?;
+        Ok(LengthPrefixedPacket {
+            data,
+            completed: true,
+            valid: true,
+        })
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn command(&self) -> Option<u8> {
+        self.data.get(2).copied()
+    }
+
+    pub fn args(&self) -> &[u8] {
+        let len = self.data.get(3).map_or(0, |len| *len as usize);
+        self.data.get(4..4 + len).unwrap_or(&[])
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.valid
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.completed
+    }
+}
+
+enum LengthPrefixedState {
+    Start,
+    Command,
+    Length,
+    Args(u8),
+    CrcLow,
+    CrcHigh,
+    Stop,
+}
+
+/// Decoder for `LengthPrefixedPacket` frames. `N` must be at least 7, the
+/// size of a frame without arguments; smaller buffers fail to compile:
+///
+/// ```compile_fail
+/// let parser = shproto_rs::LengthPrefixedParser::<4>::new();
+/// ```
+pub struct LengthPrefixedParser<const N: usize = 264> {
+    state: LengthPrefixedState,
+    data: heapless::Vec<u8, N>,
+    crc: Crc16,
+}
+
+impl<const N: usize> LengthPrefixedParser<N> {
+    pub fn new() -> Self {
+        const { assert!(N >= 7, "LengthPrefixedParser buffer N must be at least 7") };
+        LengthPrefixedParser {
+            state: LengthPrefixedState::Start,
+            data: heapless::Vec::new(),
+            crc: Crc16::new(),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.state = LengthPrefixedState::Start;
+        self.data.clear();
+        self.crc.reset();
+    }
+
+    pub fn parse_byte(
+        &mut self,
+        byte: u8,
+    ) -> Result<Option<LengthPrefixedPacket<N>>, ShprotoError> {
+        match self.state {
+            LengthPrefixedState::Start => {
+                if byte == ControlByte::START {
+                    self.reset();
+                    // cannot fail, N covers at least the header
+                    let _ = self
+                        .data
+                        .extend_from_slice(&[ControlByte::FILLER, ControlByte::START]);
+                    self.state = LengthPrefixedState::Command;
+                }
+            }
+            LengthPrefixedState::Command => {
+                self.push(byte)?;
+                self.state = LengthPrefixedState::Length;
+            }
+            LengthPrefixedState::Length => {
+                self.push(byte)?;
+                self.state = Self::args_state(byte);
+            }
+            LengthPrefixedState::Args(remaining) => {
+                self.push(byte)?;
+                self.state = Self::args_state(remaining - 1);
+            }
+            LengthPrefixedState::CrcLow => {
+                self.push(byte)?;
+                self.state = LengthPrefixedState::CrcHigh;
+            }
+            LengthPrefixedState::CrcHigh => {
+                self.push(byte)?;
+                self.state = LengthPrefixedState::Stop;
+            }
+            LengthPrefixedState::Stop => {
+                let result = if byte == ControlByte::STOP {
+                    if self.data.push(byte).is_err() {
+                        self.reset();
+                        return Err(ShprotoError::FrameTooLong);
+                    }
+                    let valid = self.crc.finalize() == 0;
+                    let data = core::mem::take(&mut self.data);
+                    Ok(Some(LengthPrefixedPacket {
+                        data,
+                        completed: true,
+                        valid,
+                    }))
+                } else {
+                    Err(ShprotoError::MalformedFrame)
+                };
+                self.reset();
+                return result;
+            }
+        }
+        Ok(None)
+    }
+
+    fn args_state(remaining: u8) -> LengthPrefixedState {
+        if remaining == 0 {
+            LengthPrefixedState::CrcLow
+        } else {
+            LengthPrefixedState::Args(remaining)
+        }
+    }
+
+    // add a CRC-covered byte, dropping the frame if it outgrows the buffer
+    fn push(&mut self, byte: u8) -> Result<(), ShprotoError> {
+        if self.data.push(byte).is_err() {
+            self.reset();
+            return Err(ShprotoError::FrameTooLong);
+        }
+        self.crc.update(byte);
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for LengthPrefixedParser<N> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -153,28 +2371,1957 @@ impl<const N: usize> ShprotoParser<N> {
 mod tests {
     use super::*;
 
+    // like `from_payload()`, pinned to CRC-16/MODBUS for fixtures with known CRC bytes
+    fn modbus_packet<const N: usize>(command: u8, args: &[u8]) -> ShprotoPacket<N> {
+        let mut packet = ShprotoPacket::with_crc_params(CrcParams::MODBUS);
+        packet.start(command).unwrap();
+        packet.add_bytes(args).unwrap();
+        packet.complete().unwrap();
+        packet
+    }
+
+    // the `03 00 01` frame under the protocol CRC, see `protocol_crc()`
+    #[cfg(not(feature = "crc-ccitt"))]
+    const FRAME: [u8; 8] = [0xFF, 0xFE, 0x03, 0x00, 0x01, 0x40, 0x00, 0xA5];
+    #[cfg(feature = "crc-ccitt")]
+    const FRAME: [u8; 8] = [0xFF, 0xFE, 0x03, 0x00, 0x01, 0xDE, 0xC7, 0xA5];
+
+    // protocol CRC of `03 99`, the payload built by `build()`
+    #[cfg(not(feature = "crc-ccitt"))]
+    const CRC_03_99: u16 = 10945;
+    #[cfg(feature = "crc-ccitt")]
+    const CRC_03_99: u16 = 0xD398;
+
+    #[test]
+    fn build() {
+        let mut packet = ShprotoPacket::<256>::with_crc_params(CrcParams::MODBUS);
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x99).unwrap();
+        assert_eq!(packet.crc.finalize(), 10945);
+        packet.complete().unwrap();
+        assert_eq!(packet.crc.finalize(), 0);
+        assert!(packet.completed);
+        assert!(packet.valid);
+    }
+
+    #[test]
+    fn parse() {
+        let bytes = [
+            0xFF, 0xFE, 0x69, 0x8C, 0x90, 0x8C, 0x89, 0xFD, 0x5A, 0x53, 0xFD, 0x02, 0xA5,
+        ];
+        let mut parser = ShprotoParser::<4096>::with_crc_params(CrcParams::MODBUS);
+        let mut packet_counter: u32 = 0;
+        for byte in bytes.as_slice() {
+            if let Some(packet) = parser.parse_byte(*byte).unwrap() {
+                assert_eq!(packet.crc.finalize(), 0);
+                packet_counter += 1;
+            }
+        }
+        assert_eq!(packet_counter, 1);
+    }
+
+    #[test]
+    fn parser_state() {
+        let mut parser = ShprotoParser::<256>::default();
+        assert_eq!(parser.state(), ParserPhase::Start);
+        parser.parse_byte(0xFE).unwrap();
+        assert_eq!(parser.state(), ParserPhase::Data);
+        parser.parse_byte(0xFD).unwrap();
+        assert_eq!(parser.state(), ParserPhase::EscapedData);
+        parser.parse_byte(0x02).unwrap();
+        assert_eq!(parser.state(), ParserPhase::Data);
+        parser.parse_byte(0xA5).unwrap();
+        assert_eq!(parser.state(), ParserPhase::Start);
+    }
+
+    #[test]
+    fn payload() {
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        packet.add_byte(0xFE).unwrap();
+        packet.add_byte(0x99).unwrap();
+        assert_eq!(packet.payload(), &[0x03, 0xFE, 0x99]);
+        packet.complete().unwrap();
+        assert_eq!(packet.payload(), &[0x03, 0xFE, 0x99]);
+
+        let mut parser = ShprotoParser::<256>::new();
+        let mut decoded = None;
+        for byte in packet.data.iter() {
+            if let Some(p) = parser.parse_byte(*byte).unwrap() {
+                decoded = Some(p);
+            }
+        }
+        assert_eq!(decoded.unwrap().payload(), &[0x03, 0xFE, 0x99]);
+    }
+
+    #[test]
+    fn payload_edge_cases() {
+        let mut packet = ShprotoPacket::<256>::new();
+        assert_eq!(packet.payload(), &[] as &[u8]);
+        packet.start(0x03).unwrap();
+        packet.complete().unwrap();
+        assert_eq!(packet.payload(), &[0x03]);
+
+        let mut parser = ShprotoParser::<256>::new();
+        parser.parse_byte(0xFE).unwrap();
+        let packet = parser.parse_byte(0xA5).unwrap().unwrap();
+        assert_eq!(packet.payload(), &[] as &[u8]);
+    }
+
+    #[test]
+    fn command() {
+        let mut packet = ShprotoPacket::<256>::new();
+        assert_eq!(packet.command(), None);
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x99).unwrap();
+        packet.complete().unwrap();
+        assert_eq!(packet.command(), Some(0x03));
+    }
+
+    #[test]
+    fn complete_overflow() {
+        let mut packet = ShprotoPacket::<8>::new();
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x01).unwrap();
+        packet.add_byte(0x02).unwrap();
+        packet.add_byte(0x03).unwrap();
+        assert!(matches!(
+            packet.complete(),
+            Err(ShprotoError::BufferOverflow)
+        ));
+        assert!(!packet.completed);
+    }
+
+    #[test]
+    fn complete_stop_overflow() {
+        // the two CRC bytes fit exactly, only STOP does not
+        let mut packet = ShprotoPacket::<8>::new();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0x01, 0x02, 0x03]).unwrap();
+        let before = packet.clone();
+        for _ in 0..2 {
+            assert_eq!(packet.complete(), Err(ShprotoError::BufferOverflow));
+            assert_eq!(packet.as_bytes(), before.as_bytes());
+            assert_eq!(packet.crc, before.crc);
+            assert_eq!(packet.crc_bytes(), None);
+        }
+        let mut roomy = ShprotoPacket::<9>::new();
+        roomy.start(0x03).unwrap();
+        roomy.add_bytes(&[0x01, 0x02, 0x03]).unwrap();
+        roomy.complete().unwrap();
+        assert_eq!(&roomy.as_bytes()[..6], before.as_bytes());
+        assert!(roomy.is_valid());
+    }
+
+    #[test]
+    fn error_display() {
+        use core::fmt::Write;
+        let mut s = heapless::String::<32>::new();
+        write!(s, "{}", ShprotoError::BufferOverflow).unwrap();
+        assert_eq!(s.as_str(), "shproto buffer overflow");
+    }
+
+    #[test]
+    fn packet_eq() {
+        let mut partial = ShprotoPacket::<256>::new();
+        partial.start(0x03).unwrap();
+        partial.add_byte(0xA5).unwrap();
+        let mut built = ShprotoPacket::<256>::new();
+        built.start(0x03).unwrap();
+        built.add_byte(0xA5).unwrap();
+        built.complete().unwrap();
+
+        let mut parser = ShprotoParser::<256>::new();
+        let mut decoded = None;
+        for byte in built.data.iter() {
+            if let Some(p) = parser.parse_byte(*byte).unwrap() {
+                decoded = Some(p);
+            }
+        }
+        assert_eq!(decoded.unwrap(), built);
+        assert_eq!(partial, built);
+
+        let mut other = ShprotoPacket::<256>::new();
+        other.start(0x04).unwrap();
+        other.complete().unwrap();
+        assert_ne!(other, built);
+    }
+
+    #[test]
+    fn as_bytes() {
+        let frame = FRAME;
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x00).unwrap();
+        packet.add_byte(0x01).unwrap();
+        packet.complete().unwrap();
+        assert_eq!(packet.as_bytes(), &frame);
+        assert!(packet.iter().eq(frame.iter().copied()));
+
+        let mut parser = ShprotoParser::<256>::new();
+        let mut decoded = None;
+        for byte in frame.iter() {
+            if let Some(p) = parser.parse_byte(*byte).unwrap() {
+                decoded = Some(p);
+            }
+        }
+        assert_eq!(decoded.unwrap().as_bytes(), &frame);
+    }
+
+    #[test]
+    fn parse_slice() {
+        let frame = FRAME;
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 4>::new();
+        parser.parse_slice(&frame[..4], &mut out).unwrap();
+        assert!(out.is_empty());
+        parser.parse_slice(&frame[4..], &mut out).unwrap();
+        assert_eq!(out.len(), 1);
+        assert_eq!(out[0].payload(), &[0x03, 0x00, 0x01]);
+    }
+
+    #[cfg(feature = "crc-table")]
+    #[test]
+    fn crc16_table_matches_bitwise() {
+        let crc = [0x03, 0x99]
+            .iter()
+            .fold(0xFFFF, |crc, b| crc16_table(crc, *b));
+        assert_eq!(crc, CRC_03_99);
+        for byte in 0..=255u8 {
+            assert_eq!(
+                crc16_table(0x1234, byte),
+                crc16_bitwise(0x1234, byte, CrcParams::PROTOCOL.poly)
+            );
+        }
+    }
+
+    #[test]
+    fn crc_params() {
+        let mut packet = ShprotoPacket::<256>::with_crc_params(CrcParams::CCITT);
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x99).unwrap();
+        assert_eq!(packet.crc.finalize(), 0xD398);
+        assert_ne!(packet.crc.finalize(), 10945);
+        packet.complete().unwrap();
+        assert!(packet.valid);
+
+        let mut parser = ShprotoParser::<256>::with_crc_params(CrcParams::CCITT);
+        let mut modbus_parser = ShprotoParser::<256>::with_crc_params(CrcParams::MODBUS);
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        let mut modbus_out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser.parse_slice(packet.as_bytes(), &mut out).unwrap();
+        modbus_parser
+            .parse_slice(packet.as_bytes(), &mut modbus_out)
+            .unwrap();
+        assert!(out[0].valid);
+        assert!(!modbus_out[0].valid);
+    }
+
+    #[test]
+    fn const_empty() {
+        const PACKET: ShprotoPacket<16> = ShprotoPacket::empty();
+        let mut packet = PACKET;
+        assert!(packet.as_bytes().is_empty());
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x99).unwrap();
+        packet.complete().unwrap();
+
+        let mut expected = ShprotoPacket::<16>::new();
+        expected.start(0x03).unwrap();
+        expected.add_byte(0x99).unwrap();
+        expected.complete().unwrap();
+        assert_eq!(packet.as_bytes(), expected.as_bytes());
+
+        // no start(): add_byte() writes the header just the same
+        let mut unstarted = PACKET;
+        unstarted.add_byte(0x03).unwrap();
+        unstarted.complete().unwrap();
+        let mut parser = ShprotoParser::<16>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<16>, 1>::new();
+        parser.parse_slice(unstarted.as_bytes(), &mut out).unwrap();
+        assert!(out[0].is_valid());
+        assert_eq!(out[0], unstarted);
+        assert_eq!(
+            ShprotoPacket::<16>::from_bytes(unstarted.as_bytes()),
+            Ok(unstarted)
+        );
+        let mut bare = PACKET;
+        bare.complete().unwrap();
+        assert_eq!(
+            bare.as_bytes(),
+            ShprotoPacket::<16>::new().finish().unwrap()
+        );
+    }
+
+    #[test]
+    fn valid_and_complete() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        parser
+            .parse_slice(&[0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5], &mut out)
+            .unwrap();
+        assert!(out[0].is_complete() && out[0].is_valid());
+        assert!(out[1].is_complete() && !out[1].is_valid());
+        assert!(!ShprotoPacket::<256>::new().is_complete());
+    }
+
+    #[test]
+    fn parser_reset() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser.parse_slice(&[0xFE, 0x03, 0xFD], &mut out).unwrap();
+        assert_eq!(parser.state(), ParserPhase::EscapedData);
+        parser.reset();
+        assert_eq!(parser.state(), ParserPhase::Start);
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        assert_eq!(out[0].payload(), &[0x03, 0x00, 0x01]);
+        assert!(out[0].is_valid());
+    }
+
+    #[test]
+    fn frame_too_long() {
+        let mut parser = ShprotoParser::<16>::new();
+        let mut errors = 0;
+        parser.parse_byte(0xFE).unwrap();
+        for _ in 0..16 + 5 {
+            if let Err(e) = parser.parse_byte(0x11) {
+                assert_eq!(e, ShprotoError::FrameTooLong);
+                errors += 1;
+            }
+        }
+        assert_eq!(errors, 1);
+        assert_eq!(parser.state(), ParserPhase::Start);
+
+        let mut out = heapless::Vec::<ShprotoPacket<16>, 1>::new();
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        assert!(out[0].is_valid());
+    }
+
+    #[test]
+    fn escaped_crc() {
+        let mut packet = ShprotoPacket::<256>::with_crc_params(CrcParams::MODBUS);
+        packet.start(0x03).unwrap();
+        packet.add_byte(0x70).unwrap();
+        packet.add_byte(0x04).unwrap();
+        assert_eq!(packet.crc.finalize().to_le_bytes()[0], ControlByte::STOP);
+        packet.complete().unwrap();
+        assert_eq!(
+            packet.as_bytes(),
+            &[0xFF, 0xFE, 0x03, 0x70, 0x04, 0xFD, 0x5A, 0xC3, 0xA5]
+        );
+
+        let mut parser = ShprotoParser::<256>::with_crc_params(CrcParams::MODBUS);
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser.parse_slice(packet.as_bytes(), &mut out).unwrap();
+        assert!(out[0].is_valid());
+        assert_eq!(out[0].payload(), &[0x03, 0x70, 0x04]);
+    }
+
+    #[test]
+    fn strict_crc_mismatch() {
+        let corrupt = [0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5];
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser.set_strict(true);
+        assert_eq!(
+            parser.parse_slice(&corrupt, &mut out),
+            Err(ShprotoError::CrcMismatch)
+        );
+        assert!(out.is_empty());
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        assert!(out[0].is_valid());
+
+        parser.set_strict(false);
+        out.clear();
+        parser.parse_slice(&corrupt, &mut out).unwrap();
+        assert!(!out[0].is_valid());
+    }
+
+    #[test]
+    fn from_payload() {
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0x99]).unwrap();
+        let mut expected = ShprotoPacket::<256>::new();
+        expected.start(0x03).unwrap();
+        expected.add_byte(0x99).unwrap();
+        expected.complete().unwrap();
+        assert_eq!(packet.as_bytes(), expected.as_bytes());
+        assert!(packet.is_complete() && packet.is_valid());
+        assert_eq!(
+            ShprotoPacket::<8>::from_payload(0x03, &[0x01, 0x02, 0x03, 0x04]),
+            Err(ShprotoError::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn add_bytes() {
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0x01, 0xFD, 0x02]).unwrap();
+        let mut expected = ShprotoPacket::<256>::new();
+        expected.start(0x03).unwrap();
+        expected.add_byte(0x01).unwrap();
+        expected.add_byte(0xFD).unwrap();
+        expected.add_byte(0x02).unwrap();
+        assert_eq!(packet.as_bytes(), expected.as_bytes());
+        assert_eq!(
+            packet.as_bytes(),
+            &[0xFF, 0xFE, 0x03, 0x01, 0xFD, 0x02, 0x02]
+        );
+        assert_eq!(packet.crc, expected.crc);
+    }
+
+    #[test]
+    fn len() {
+        let packet = ShprotoPacket::<256>::new();
+        assert_eq!(packet.len(), 0);
+        assert!(packet.is_empty());
+
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser.parse_slice(&FRAME, &mut out).unwrap();
+        assert_eq!(out[0].len(), 3);
+        assert!(!out[0].is_empty());
+    }
+
+    #[test]
+    fn clear() {
+        let mut packet = ShprotoPacket::<256>::from_payload(0x03, &[0x99]).unwrap();
+        packet.clear();
+        assert!(!packet.is_complete());
+        packet.start(0x04).unwrap();
+        packet.add_bytes(&[0xFE, 0x01]).unwrap();
+        packet.complete().unwrap();
+        let fresh = ShprotoPacket::<256>::from_payload(0x04, &[0xFE, 0x01]).unwrap();
+        assert_eq!(packet.as_bytes(), fresh.as_bytes());
+        assert!(packet.is_valid());
+    }
+
+    #[test]
+    fn packet_default() {
+        let packet = ShprotoPacket::<64>::default();
+        assert_eq!(packet.as_bytes(), ShprotoPacket::<64>::new().as_bytes());
+    }
+
+    #[test]
+    fn verify_crc() {
+        let mut packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        assert!(packet.verify_crc());
+        // flip the low bit of the last payload byte on the wire
+        packet.data[4] ^= 0x01;
+        assert!(!packet.verify_crc());
+        packet.data[4] ^= 0x01;
+        assert!(packet.verify_crc());
+        let escaped = ShprotoPacket::<256>::from_payload(0xFE, &[0xA5, 0xFD]).unwrap();
+        assert!(escaped.verify_crc());
+        assert!(!ShprotoPacket::<256>::new().verify_crc());
+    }
+
+    #[test]
+    fn minimal_packets() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+
+        let command_only = ShprotoPacket::<256>::from_payload(0x03, &[]).unwrap();
+        parser
+            .parse_slice(command_only.as_bytes(), &mut out)
+            .unwrap();
+
+        let mut empty = ShprotoPacket::<256>::new();
+        empty.complete().unwrap();
+        assert_eq!(empty.as_bytes(), &[0xFF, 0xFE, 0xFF, 0xFF, 0xA5]);
+        parser.parse_slice(empty.as_bytes(), &mut out).unwrap();
+
+        assert_eq!(out[0].payload(), &[0x03]);
+        assert!(out[0].is_valid());
+        assert_eq!(out[1].payload(), &[] as &[u8]);
+        assert_eq!(out[1].command(), None);
+        assert!(out[1].is_valid());
+    }
+
+    #[test]
+    fn inter_byte_timeout() {
+        let mut parser = ShprotoParser::<256>::new();
+        parser.set_timeout(Some(10));
+        let second = ShprotoPacket::<256>::from_payload(0x04, &[0x05]).unwrap();
+        let mut decoded = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        let mut now = 0;
+        for byte in &FRAME[1..4] {
+            now += 1;
+            assert_eq!(parser.parse_byte_at(*byte, now), Ok(None));
+        }
+        // without the timeout this tail would complete the first frame
+        now += 100;
+        for byte in FRAME[4..].iter().chain(second.as_bytes()) {
+            if let Some(packet) = parser.parse_byte_at(*byte, now).unwrap() {
+                decoded.push(packet).unwrap();
+            }
+            now += 1;
+        }
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].payload(), &[0x04, 0x05]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn error_boxed() {
+        fn fails() -> Result<(), std::boxed::Box<dyn std::error::Error>> {
+            Err(ShprotoError::BufferOverflow)?;
+            Ok(())
+        }
+        assert!(fails().is_err());
+    }
+
+    #[test]
+    fn stream_decoder() {
+        let mut stream = heapless::Vec::<u8, 32>::new();
+        stream
+            .extend_from_slice(
+                ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01])
+                    .unwrap()
+                    .as_bytes(),
+            )
+            .unwrap();
+        stream
+            .extend_from_slice(
+                ShprotoPacket::<256>::from_payload(0x04, &[0xFE])
+                    .unwrap()
+                    .as_bytes(),
+            )
+            .unwrap();
+        let mut decoder = StreamDecoder::<_, 256>::new(stream.iter().copied());
+        assert_eq!(
+            decoder.next().unwrap().unwrap().payload(),
+            &[0x03, 0x00, 0x01]
+        );
+        assert_eq!(decoder.next().unwrap().unwrap().payload(), &[0x04, 0xFE]);
+        assert!(decoder.next().is_none());
+    }
+
+    #[cfg(feature = "embedded-hal")]
+    #[test]
+    fn read_packet_nb() {
+        struct MockSerial<'a>(&'a [u8]);
+        impl embedded_hal::serial::Read<u8> for MockSerial<'_> {
+            type Error = ();
+            fn read(&mut self) -> nb::Result<u8, ()> {
+                let (byte, rest) = self.0.split_first().ok_or(nb::Error::WouldBlock)?;
+                self.0 = rest;
+                Ok(*byte)
+            }
+        }
+
+        let frame = FRAME;
+        let mut parser = ShprotoParser::<256>::new();
+        let mut serial = MockSerial(&frame[..4]);
+        assert_eq!(parser.read_packet(&mut serial), Err(nb::Error::WouldBlock));
+        let mut serial = MockSerial(&frame[4..]);
+        let packet = parser.read_packet(&mut serial).unwrap();
+        assert_eq!(packet.payload(), &[0x03, 0x00, 0x01]);
+        assert_eq!(parser.read_packet(&mut serial), Err(nb::Error::WouldBlock));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn read_packet_async() {
+        use core::future::Future;
+        use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+        struct FakeReader<'a>(&'a [u8]);
+        impl embedded_io_async::ErrorType for FakeReader<'_> {
+            type Error = core::convert::Infallible;
+        }
+        impl embedded_io_async::Read for FakeReader<'_> {
+            async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+                let len = self.0.len().min(buf.len()).min(5);
+                buf[..len].copy_from_slice(&self.0[..len]);
+                self.0 = &self.0[len..];
+                Ok(len)
+            }
+        }
+
+        fn block_on<F: Future>(future: F) -> F::Output {
+            fn clone(_: *const ()) -> RawWaker {
+                RawWaker::new(core::ptr::null(), &VTABLE)
+            }
+            fn noop(_: *const ()) {}
+            static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+            let waker = unsafe { Waker::from_raw(clone(core::ptr::null())) };
+            let mut cx = Context::from_waker(&waker);
+            let mut future = core::pin::pin!(future);
+            loop {
+                if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                    return output;
+                }
+            }
+        }
+
+        let mut stream = heapless::Vec::<u8, 16>::new();
+        stream.extend_from_slice(&FRAME).unwrap();
+        stream.extend_from_slice(&FRAME).unwrap();
+        let mut reader = FakeReader(&stream);
+        let mut parser = ShprotoParser::<256>::new();
+        for _ in 0..2 {
+            let packet = block_on(parser.read_packet_async(&mut reader)).unwrap();
+            assert_eq!(packet.payload(), &[0x03, 0x00, 0x01]);
+        }
+        assert_eq!(
+            block_on(parser.read_packet_async(&mut reader)),
+            Err(ShprotoError::Io)
+        );
+    }
+
+    #[test]
+    fn packet_builder() {
+        let packet = PacketBuilder::<256>::new()
+            .command(0x03)
+            .arg(0x00)
+            .args(&[0x01])
+            .build()
+            .unwrap();
+        assert_eq!(packet.as_bytes(), &FRAME);
+        let mut expected = ShprotoPacket::<256>::new();
+        expected.start(0x03).unwrap();
+        expected.add_bytes(&[0x00, 0x01]).unwrap();
+        expected.complete().unwrap();
+        assert_eq!(packet.as_bytes(), expected.as_bytes());
+
+        let result = PacketBuilder::<8>::new()
+            .command(0x03)
+            .args(&[0xFE; 4])
+            .arg(0x01)
+            .build();
+        assert_eq!(result, Err(ShprotoError::BufferOverflow));
+    }
+
+    #[test]
+    fn encoder() {
+        for (command, args) in [
+            (0x03, &[0x00, 0x01][..]),
+            (0xFE, &[0xA5, 0xFD, 0x99][..]),
+            (0x03, &[0x70, 0x04][..]),
+        ] {
+            let encoded: heapless::Vec<u8, 32> = Encoder::new(command, args).collect();
+            let packet = ShprotoPacket::<32>::from_payload(command, args).unwrap();
+            assert_eq!(encoded.as_slice(), packet.as_bytes());
+        }
+    }
+
+    #[test]
+    fn parser_stats() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        parser.parse_slice(&[0x11, 0x22, 0x33], &mut out).unwrap();
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        parser
+            .parse_slice(&[0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5], &mut out)
+            .unwrap();
+        let stats = parser.stats();
+        assert_eq!(stats.bytes_dropped, 3);
+        assert_eq!(stats.frames_decoded, 1);
+        assert_eq!(stats.crc_errors, 1);
+    }
+
+    #[test]
+    fn parse_byte_event() {
+        let mut parser = ShprotoParser::<256>::new();
+        assert_eq!(parser.parse_byte_event(0x11), Ok(ParseEvent::Dropped(0x11)));
+        assert_eq!(parser.parse_byte_event(0x22), Ok(ParseEvent::Dropped(0x22)));
+        for byte in &FRAME[1..7] {
+            assert_eq!(parser.parse_byte_event(*byte), Ok(ParseEvent::None));
+        }
+        match parser.parse_byte_event(0xA5) {
+            Ok(ParseEvent::Packet(packet)) => assert_eq!(packet.payload(), &[0x03, 0x00, 0x01]),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn into_inner() {
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        let data = packet.into_inner();
+        assert_eq!(data.as_slice(), &FRAME);
+    }
+
+    #[test]
+    fn from_bytes() {
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0xFE, 0x70, 0x04]).unwrap();
+        let wrapped = ShprotoPacket::<256>::from_bytes(packet.as_bytes()).unwrap();
+        assert_eq!(wrapped.as_bytes(), packet.as_bytes());
+        assert_eq!(wrapped, packet);
+        assert!(wrapped.is_complete() && wrapped.is_valid());
+        let unfilled = ShprotoPacket::<256>::from_bytes(&packet.as_bytes()[1..]).unwrap();
+        assert_eq!(unfilled, packet);
+
+        let corrupt =
+            ShprotoPacket::<256>::from_bytes(&[0xFF, 0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5])
+                .unwrap();
+        assert!(!corrupt.is_valid());
+        for bad in [
+            &[0x00, 0xFE, 0x03, 0xA5][..],
+            &[0xFF, 0xFE, 0x03],
+            &[0xFF, 0xFE, 0x03, 0xFD, 0xA5],
+            &[0xFF, 0xFE, 0xFE, 0xA5],
+        ] {
+            assert_eq!(
+                ShprotoPacket::<256>::from_bytes(bad),
+                Err(ShprotoError::MalformedFrame)
+            );
+        }
+    }
+
+    #[test]
+    fn packet_debug() {
+        use core::fmt::Write;
+        let packet = modbus_packet::<256>(0x03, &[0x00, 0xFE]);
+        let mut s = heapless::String::<128>::new();
+        write!(s, "{:?}", packet).unwrap();
+        assert_eq!(
+            s.as_str(),
+            "ShprotoPacket { command: Some(03), payload: [03, 00, FE], crc: 4000, valid: true, completed: true }"
+        );
+    }
+
+    #[test]
+    fn to_hex() {
+        let packet = modbus_packet::<256>(0x03, &[0x00, 0x01]);
+        assert_eq!(
+            packet.to_hex::<32>().unwrap().as_str(),
+            "FF FE 03 00 01 40 00 A5"
+        );
+        assert_eq!(packet.to_hex::<8>(), Err(ShprotoError::BufferOverflow));
+    }
+
+    #[test]
+    fn crc16_update() {
+        let crc = [0x03, 0x99]
+            .iter()
+            .fold(0xFFFF, |crc, byte| super::crc16_update(crc, *byte));
+        assert_eq!(crc, CRC_03_99);
+        assert_eq!(crc16(0xFFFF, 0x03), super::crc16_update(0xFFFF, 0x03));
+    }
+
+    #[test]
+    fn custom_framing() {
+        struct AltFraming;
+        impl Framing for AltFraming {
+            const START: u8 = 0x7E;
+            const ESCAPE: u8 = 0x7D;
+            const STOP: u8 = 0x7F;
+        }
+
+        let packet =
+            ShprotoPacket::<64, AltFraming>::from_payload(0x03, &[0x7E, 0xFE, 0x7F]).unwrap();
+        assert_eq!(
+            &packet.as_bytes()[..8],
+            &[0xFF, 0x7E, 0x03, 0x7D, 0x81, 0xFE, 0x7D, 0x80]
+        );
+        assert_eq!(packet.as_bytes().last(), Some(&0x7F));
+        let encoded: heapless::Vec<u8, 64> =
+            Encoder::<AltFraming>::with_framing(0x03, &[0x7E, 0xFE, 0x7F], CrcParams::PROTOCOL)
+                .collect();
+        assert_eq!(encoded.as_slice(), packet.as_bytes());
+
+        let mut parser = ShprotoParser::<64, AltFraming>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<64, AltFraming>, 1>::new();
+        parser.parse_slice(packet.as_bytes(), &mut out).unwrap();
+        assert!(out[0].is_valid());
+        assert_eq!(out[0].payload(), &[0x03, 0x7E, 0xFE, 0x7F]);
+
+        let mut default_parser = ShprotoParser::<64>::new();
+        let mut default_out = heapless::Vec::<ShprotoPacket<64>, 1>::new();
+        default_parser
+            .parse_slice(packet.as_bytes(), &mut default_out)
+            .unwrap();
+        assert!(default_out.is_empty());
+    }
+
+    #[test]
+    fn escape_then_start() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser
+            .parse_slice(&[0xFE, 0x04, 0x05, 0xFD], &mut out)
+            .unwrap();
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        assert_eq!(out.len(), 1);
+        assert!(out[0].is_valid());
+        assert_eq!(out[0].payload(), &[0x03, 0x00, 0x01]);
+    }
+
     #[test]
-    fn build() {
+    fn invalid_escape() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        assert_eq!(
+            parser.parse_slice(&[0xFE, 0x03, 0xFD, 0x00], &mut out),
+            Err(ShprotoError::InvalidEscape)
+        );
+        assert_eq!(parser.state(), ParserPhase::Start);
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        assert!(out[0].is_valid());
+        assert_eq!(
+            ShprotoPacket::<256>::from_bytes(&[0xFF, 0xFE, 0x03, 0xFD, 0x00, 0xA5]),
+            Err(ShprotoError::InvalidEscape)
+        );
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn packet_vec() {
+        let args: alloc::vec::Vec<u8> = (0..600u16).map(|i| i as u8).collect();
+        let mut packet = ShprotoPacketVec::new();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&args).unwrap();
+        packet.complete().unwrap();
+        assert!(packet.is_valid());
+        assert_eq!(packet.command(), Some(0x03));
+        assert_eq!(&packet.payload()[1..], args.as_slice());
+
+        let decoded = ShprotoPacketVec::from_bytes(packet.as_bytes()).unwrap();
+        assert_eq!(decoded, packet);
+        assert_eq!(packet.add_byte(0x01), Err(ShprotoError::AlreadyComplete));
+        assert_eq!(packet.start(0x04), Err(ShprotoError::AlreadyComplete));
+        assert_eq!(packet.complete(), Err(ShprotoError::AlreadyComplete));
+        assert_eq!(packet, decoded);
+        assert_eq!(packet.as_bytes(), decoded.as_bytes());
+
+        let mut partial = ShprotoPacketVec::new();
+        partial.start(0x03).unwrap();
+        assert_eq!(partial.start(0x03), Err(ShprotoError::AlreadyStarted));
+        partial.add_bytes(&args).unwrap();
+        let mut escaped = partial.clone();
+        escaped.data.push(0x00);
+        assert_eq!(escaped, partial);
+
+        let small = ShprotoPacket::<64>::from_payload(0x03, &[0xFE, 0x01]).unwrap();
+        let mut big = ShprotoPacketVec::new();
+        big.start(0x03).unwrap();
+        big.add_bytes(&[0xFE, 0x01]).unwrap();
+        big.complete().unwrap();
+        assert_eq!(big.as_bytes(), small.as_bytes());
+    }
+
+    #[test]
+    fn packet_clone() {
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0xFE, 0x99]).unwrap();
+        let copy = packet.clone();
+        assert_eq!(copy, packet);
+        assert_eq!(copy.as_bytes(), packet.as_bytes());
+        assert_eq!(copy.crc, packet.crc);
+        assert_eq!(copy.is_valid(), packet.is_valid());
+        assert_eq!(copy.is_complete(), packet.is_complete());
+    }
+
+    #[test]
+    fn matches_command() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser.parse_slice(&FRAME, &mut out).unwrap();
+        assert!(out[0].matches_command(0x03));
+        assert!(!out[0].matches_command(0x04));
+        assert!(!ShprotoPacket::<256>::new().matches_command(0x03));
+    }
+
+    #[test]
+    fn packet_hash() {
+        let built = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        let decoded = ShprotoPacket::<256>::from_bytes(built.as_bytes()).unwrap();
+        let other = ShprotoPacket::<256>::from_payload(0x04, &[]).unwrap();
+        let mut seen = heapless::FnvIndexSet::<ShprotoPacket<256>, 4>::new();
+        seen.insert(built).unwrap();
+        seen.insert(decoded).unwrap();
+        assert_eq!(seen.len(), 1);
+        seen.insert(other).unwrap();
+        assert_eq!(seen.len(), 2);
+    }
+
+    #[test]
+    fn split() {
+        let packet = ShprotoPacket::<256>::from_bytes(&FRAME).unwrap();
+        assert_eq!(packet.split(), Some((0x03, &[0x00, 0x01][..])));
+        assert_eq!(ShprotoPacket::<256>::new().split(), None);
+    }
+
+    #[test]
+    fn length_prefixed() {
+        let mut args = [0u8; 255];
+        for (i, byte) in args.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let mut parser = LengthPrefixedParser::<264>::new();
+        for args in [&[0xFE, 0xA5, 0xFD][..], &[], &args] {
+            let packet = LengthPrefixedPacket::<264>::from_payload(0x03, args).unwrap();
+            assert_eq!(packet.as_bytes().len(), args.len() + 7);
+            let mut decoded = None;
+            for byte in packet.as_bytes() {
+                if let Some(p) = parser.parse_byte(*byte).unwrap() {
+                    decoded = Some(p);
+                }
+            }
+            let decoded = decoded.unwrap();
+            assert!(decoded.is_valid());
+            assert_eq!(decoded.command(), Some(0x03));
+            assert_eq!(decoded.args(), args);
+            assert_eq!(decoded.as_bytes(), packet.as_bytes());
+        }
+        assert_eq!(
+            LengthPrefixedPacket::<264>::from_payload(0x03, &[0; 256]).err(),
+            Some(ShprotoError::BufferOverflow)
+        );
+        let mut result = Ok(false);
+        for byte in [0xFE, 0x03, 0x00, 0xFF, 0x41, 0x00] {
+            result = parser.parse_byte(byte).map(|p| p.is_some());
+        }
+        assert_eq!(result, Err(ShprotoError::MalformedFrame));
+    }
+
+    #[test]
+    fn in_progress() {
+        let mut parser = ShprotoParser::<256>::new();
+        assert!(parser.in_progress().is_none());
+        parser.parse_byte(0xFE).unwrap();
+        assert_eq!(parser.in_progress().unwrap().len(), 0);
+        parser.parse_byte(0x03).unwrap();
+        assert_eq!(parser.in_progress().unwrap().len(), 1);
+        parser.parse_byte(0xFD).unwrap();
+        assert_eq!(parser.in_progress().unwrap().len(), 1);
+        parser.parse_byte(0x02).unwrap();
+        assert_eq!(parser.in_progress().unwrap().len(), 2);
+        assert_eq!(parser.in_progress().unwrap().payload(), &[0x03, 0xFD]);
+    }
+
+    #[test]
+    fn report_aborted() {
+        let frame = &FRAME[1..];
+        let mut parser = ShprotoParser::<256>::new();
+        parser.set_report_aborted(true);
+        for byte in &FRAME[1..6] {
+            assert_eq!(parser.parse_byte(*byte), Ok(None));
+        }
+        let aborted = parser.parse_byte(frame[0]).unwrap().unwrap();
+        assert!(!aborted.is_complete() && !aborted.is_valid());
+        assert_eq!(aborted.as_bytes(), &FRAME[..6]);
+        let packet = frame[1..]
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(packet.is_valid());
+
+        let mut lenient = ShprotoParser::<256>::new();
+        let packet = [0xFE, 0x03, 0xFD]
+            .iter()
+            .chain(frame.iter())
+            .find_map(|b| lenient.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(packet.is_valid());
+    }
+
+    #[cfg(all(feature = "serde", feature = "std"))]
+    #[test]
+    fn serde_round_trip() {
+        let packet = modbus_packet::<256>(0x03, &[0x00, 0xFE]);
+        let json = serde_json::to_string(&packet).unwrap();
+        assert_eq!(
+            json,
+            r#"{"command":3,"payload":[3,0,254],"crc":16384,"valid":true,"completed":true}"#
+        );
+        let decoded: ShprotoPacket<256> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.as_bytes(), packet.as_bytes());
+        assert_eq!(decoded, packet);
+        assert!(decoded.is_valid() && decoded.is_complete());
+
+        let corrupt =
+            ShprotoPacket::<256>::from_bytes(&[0xFF, 0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5])
+                .unwrap();
+        let decoded: ShprotoPacket<256> =
+            serde_json::from_str(&serde_json::to_string(&corrupt).unwrap()).unwrap();
+        assert_eq!(decoded.as_bytes(), corrupt.as_bytes());
+        assert!(!decoded.is_valid());
+    }
+
+    #[test]
+    fn encode_into() {
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0xFE, 0x00, 0xA5]).unwrap();
+        let mut out = [0u8; 32];
+        let len = super::encode_into(0x03, &[0xFE, 0x00, 0xA5], &mut out).unwrap();
+        assert_eq!(&out[..len], packet.as_bytes());
+        let mut short = [0u8; 8];
+        assert_eq!(
+            super::encode_into(0x03, &[0xFE, 0x00, 0xA5], &mut short),
+            Err(ShprotoError::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn buffered_decoder() {
+        let frame = FRAME;
+        let mut decoder = BufferedDecoder::<256, 1>::new();
+        for byte in &frame[..5] {
+            decoder.push(*byte).unwrap();
+        }
+        assert!(decoder.pop().is_none());
+        for byte in &frame[5..] {
+            decoder.push(*byte).unwrap();
+        }
+        for byte in &frame[..7] {
+            decoder.push(*byte).unwrap();
+        }
+        assert_eq!(decoder.pop().unwrap().payload(), &[0x03, 0x00, 0x01]);
+        assert!(decoder.pop().is_none());
+        decoder.push(0xA5).unwrap();
+        decoder.push(0xFE).unwrap();
+        assert!(decoder.pop().unwrap().is_valid());
+
+        let mut full = BufferedDecoder::<256, 1>::new();
+        let errors = frame
+            .iter()
+            .chain(frame.iter())
+            .filter(|b| full.push(**b).is_err())
+            .count();
+        assert_eq!(errors, 1);
+    }
+
+    #[test]
+    fn heartbeat() {
+        let packet = ShprotoPacket::<256>::heartbeat(0x05).unwrap();
+        assert!(packet.is_valid() && packet.is_complete());
+        let mut parser = ShprotoParser::<256>::new();
+        let decoded = packet
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert_eq!(decoded.split(), Some((0x05, &[][..])));
+    }
+
+    #[test]
+    fn ack_nack() {
+        let mut parser = ShprotoParser::<256>::new();
+        let ack = ShprotoPacket::<256>::ack(0xFE).unwrap();
+        let decoded = ack
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(decoded.is_ack() && !decoded.is_nack());
+        assert_eq!(decoded.split(), Some((ReservedCommand::ACK, &[0xFE][..])));
+
+        let nack = ShprotoPacket::<256>::nack(0x01, 0x02).unwrap();
+        let decoded = nack
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(decoded.is_nack() && !decoded.is_ack());
+        assert!(
+            !ShprotoPacket::<256>::from_payload(ReservedCommand::ACK, &[])
+                .unwrap()
+                .is_ack()
+        );
+    }
+
+    #[test]
+    fn fragment_reassemble() {
+        let mut message = [0u8; 600];
+        for (i, byte) in message.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        let fragments = Fragmenter::<64>::new(0x07, &message)
+            .unwrap()
+            .collect::<Result<heapless::Vec<_, 32>, _>>()
+            .unwrap();
+        assert_eq!(fragments.len(), 24);
+
+        let mut buf = [0u8; 600];
+        let mut reassembler = Reassembler::new(&mut buf);
+        let mut parser = ShprotoParser::<64>::new();
+        let mut result = None;
+        for fragment in &fragments {
+            let decoded = fragment
+                .as_bytes()
+                .iter()
+                .find_map(|b| parser.parse_byte(*b).unwrap())
+                .unwrap();
+            assert_eq!(result, None);
+            result = reassembler
+                .push(&decoded)
+                .unwrap()
+                .map(|m| m == &message[..]);
+        }
+        assert_eq!(result, Some(true));
+
+        assert!(reassembler.push(&fragments[0]).unwrap().is_none());
+        assert_eq!(
+            reassembler.push(&fragments[2]),
+            Err(ShprotoError::UnexpectedFragment)
+        );
+        assert_eq!(
+            reassembler.push(&fragments[1]),
+            Err(ShprotoError::UnexpectedFragment)
+        );
+    }
+
+    #[test]
+    fn sequence() {
+        let packet = ShprotoPacket::<256>::with_sequence(0x03, 0xFE, &[0x01, 0x00]).unwrap();
+        assert_eq!(packet.payload(), &[0x03, 0xFE, 0x01, 0x00]);
+        assert_eq!(packet.split(), Some((0x03, &[0x01, 0x00][..])));
+        assert_eq!(packet.payload_u16_le(0), Some(0x0001));
+        let mut parser = ShprotoParser::<256>::new();
+        parser.set_sequenced(true);
+        let decoded = packet
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(decoded.is_valid());
+        assert_eq!(decoded.sequence(), Some(0xFE));
+        assert_eq!(decoded, packet);
+        assert_eq!(
+            ShprotoPacket::<256>::heartbeat(0x03).unwrap().sequence(),
+            None
+        );
+        let plain = ShprotoPacket::<256>::from_payload(0x03, &[0x99]).unwrap();
+        assert_eq!(plain.sequence(), None);
+        assert_eq!(
+            ShprotoPacket::<256>::from_bytes(packet.as_bytes())
+                .unwrap()
+                .sequence(),
+            None
+        );
+        let mut reused = packet.clone();
+        reused.clear();
+        reused.start(0x03).unwrap();
+        reused.add_byte(0x99).unwrap();
+        reused.complete().unwrap();
+        assert_eq!(reused.sequence(), None);
+        assert_eq!(reused, plain);
+    }
+
+    #[test]
+    fn packets() {
+        let frame = FRAME;
+        let mut bytes = heapless::Vec::<u8, 32>::new();
+        bytes.extend_from_slice(&frame).unwrap();
+        bytes.extend_from_slice(&frame).unwrap();
+        bytes.extend_from_slice(&[0x11, 0xFE, 0x22]).unwrap();
+        let decoded = super::packets::<256>(&bytes).collect::<heapless::Vec<_, 4>>();
+        assert_eq!(decoded.len(), 2);
+        assert!(decoded
+            .iter()
+            .all(|p| p.as_ref().is_ok_and(|p| p.is_valid())));
+    }
+
+    #[test]
+    fn add_byte_no_partial_escape() {
+        let mut packet = ShprotoPacket::<8>::new();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0x00, 0x01, 0x02, 0x04]).unwrap();
+        let before = packet.clone();
+        assert_eq!(packet.add_byte(0xFE), Err(ShprotoError::BufferOverflow));
+        assert_eq!(packet.as_bytes(), before.as_bytes());
+        assert_eq!(packet, before);
+        packet.add_byte(0x05).unwrap();
+        assert_eq!(packet.add_byte(0x06), Err(ShprotoError::BufferOverflow));
+    }
+
+    #[test]
+    fn take_partial() {
+        let mut parser = ShprotoParser::<256>::new();
+        assert!(parser.take_partial().is_none());
+        for byte in [0xFF, 0xFE, 0x03, 0x00, 0x01] {
+            parser.parse_byte(byte).unwrap();
+        }
+        let partial = parser.take_partial().unwrap();
+        assert_eq!(partial.len(), 3);
+        assert!(!partial.is_complete() && !partial.is_valid());
+        assert_eq!(parser.state(), ParserPhase::Start);
+        assert!(parser.in_progress().is_none());
+        assert_eq!(
+            (parser.raw_byte_count(), parser.logical_byte_count()),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn protocol_crc() {
+        let crc = [0x03, 0x00, 0x01]
+            .iter()
+            .fold(CrcParams::PROTOCOL.init, |crc, byte| crc16(crc, *byte));
+        #[cfg(not(feature = "crc-ccitt"))]
+        assert_eq!(crc, 0x0040);
+        #[cfg(feature = "crc-ccitt")]
+        assert_eq!(crc, 0xC7DE);
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        assert_eq!(&packet.as_bytes()[5..7], &crc.to_le_bytes());
+    }
+
+    #[test]
+    fn add_int_le() {
         let mut packet = ShprotoPacket::<256>::new();
         packet.start(0x03).unwrap();
-        packet.add_byte(0x99).unwrap();
-        assert_eq!(packet.crc, 10945);
-        packet.complete();
-        assert_eq!(packet.crc, 0);
-        assert_eq!(packet.completed, true);
-        assert_eq!(packet.valid, true);
+        packet.add_u16_le(0xFEA5).unwrap();
+        packet.add_u32_le(0x0403_0201).unwrap();
+        packet.complete().unwrap();
+        let mut manual = ShprotoPacket::<256>::new();
+        manual.start(0x03).unwrap();
+        for byte in [0xA5, 0xFE, 0x01, 0x02, 0x03, 0x04] {
+            manual.add_byte(byte).unwrap();
+        }
+        manual.complete().unwrap();
+        assert_eq!(packet.as_bytes(), manual.as_bytes());
+        assert!(packet.is_valid());
     }
+
     #[test]
-    fn parse() {
-        let bytes = [0xFF, 0xFE, 0x69, 0x8C, 0x90, 0x8C, 0x89, 0xFD, 0x5A, 0x53, 0xFD, 0x02, 0xA5];
-        let mut parser = ShprotoParser::<4096>::new();
-        let mut packet_counter: u32 = 0;
-        for byte in bytes.as_slice() {
-            if let Some(packet) = parser.parse_byte(*byte).unwrap() {
-                assert_eq!(packet.crc, 0);
-                packet_counter += 1;
+    fn payload_int_le() {
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        packet.add_u16_le(0xFEA5).unwrap();
+        packet.add_u32_le(0x0403_0201).unwrap();
+        packet.complete().unwrap();
+        let mut parser = ShprotoParser::<256>::new();
+        let decoded = packet
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert_eq!(decoded.payload_u16_le(0), Some(0xFEA5));
+        assert_eq!(decoded.payload_u32_le(2), Some(0x0403_0201));
+        assert_eq!(decoded.payload_u16_le(5), None);
+        assert_eq!(decoded.payload_u32_le(usize::MAX), None);
+    }
+
+    #[test]
+    fn error_variants() {
+        let mut out = heapless::Vec::<ShprotoPacket<16>, 1>::new();
+        let cases: [(&[u8], ShprotoError); 5] = [
+            (&[0xFE, 0xA5], ShprotoError::MalformedFrame),
+            (&[0xFE, 0x03, 0x00, 0xFE], ShprotoError::MalformedFrame),
+            (&[0xFE, 0x03, 0xFD, 0x00], ShprotoError::InvalidEscape),
+            (
+                &[0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5],
+                ShprotoError::CrcMismatch,
+            ),
+            (
+                &[
+                    0xFE, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x11,
+                    0x11, 0x11, 0x11,
+                ],
+                ShprotoError::FrameTooLong,
+            ),
+        ];
+        for (bytes, error) in cases {
+            let mut parser = ShprotoParser::<16>::new();
+            parser.set_strict(true);
+            assert_eq!(parser.parse_slice(bytes, &mut out), Err(error));
+            let crc_errors = u32::from(error == ShprotoError::CrcMismatch);
+            assert_eq!(parser.stats().crc_errors, crc_errors);
+        }
+        assert_eq!(
+            ShprotoPacket::<16>::from_bytes(&[0x00, 0xFE, 0x03, 0xA5]),
+            Err(ShprotoError::MalformedFrame)
+        );
+        assert!(out.is_empty());
+
+        let mut lenient = ShprotoParser::<16>::new();
+        lenient.parse_slice(&[0xFE, 0xA5], &mut out).unwrap();
+        assert!(!out[0].is_valid());
+    }
+
+    #[test]
+    fn optional_filler() {
+        let with_filler = FRAME;
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        let mut parser = ShprotoParser::<256>::new();
+        parser.parse_slice(&with_filler, &mut out).unwrap();
+        parser.parse_slice(&with_filler[1..], &mut out).unwrap();
+        assert_eq!(out[0], out[1]);
+        assert_eq!(out[0].as_bytes(), out[1].as_bytes());
+        assert_eq!(parser.stats().bytes_dropped, 0);
+        assert_eq!(parser.parse_byte_event(0xFF), Ok(ParseEvent::None));
+    }
+
+    #[test]
+    fn payload_sized_packet() {
+        let args = [0xFE; 31];
+        let packet = <shproto_packet!(32)>::from_payload(0xFE, &args).unwrap();
+        assert_eq!(packet.data.capacity(), 71);
+        assert_eq!(packet.payload().len(), 32);
+        assert!(packet.payload().iter().all(|b| *b == 0xFE));
+        assert!(packet.is_valid());
+        assert!(<shproto_packet!(32)>::from_payload(0xFE, &[0xFE; 33]).is_err());
+    }
+
+    #[test]
+    fn payload_iter() {
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0xFE, 0x00, 0xA5]).unwrap();
+        let bytes: heapless::Vec<u8, 8> = packet.payload_iter().collect();
+        assert_eq!(bytes.as_slice(), packet.payload());
+        assert_eq!(bytes.as_slice(), &[0x03, 0xFE, 0x00, 0xA5]);
+    }
+
+    #[test]
+    fn eq_payload_slice() {
+        let mut parser = ShprotoParser::<256>::new();
+        let frame = FRAME;
+        let packet = frame
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert_eq!(packet, [0x03, 0x00, 0x01].as_slice());
+        assert!(packet == *[0x03, 0x00, 0x01].as_slice());
+        assert_ne!(packet, frame.as_slice());
+    }
+
+    #[test]
+    fn no_panic_fuzz() {
+        let mut seed: u32 = 0x1234_5678;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            // bias towards control bytes so escapes and frames actually occur
+            match seed % 8 {
+                0 => 0xFE,
+                1 => 0xFD,
+                2 => 0xA5,
+                _ => (seed >> 8) as u8,
+            }
+        };
+        let mut parser = ShprotoParser::<16>::new();
+        let mut strict = ShprotoParser::<16>::new();
+        strict.set_strict(true);
+        strict.set_report_aborted(true);
+        let mut length_prefixed = LengthPrefixedParser::<16>::new();
+        let mut buf = [0u8; 64];
+        let mut reassembler = Reassembler::new(&mut buf);
+        for _ in 0..5000 {
+            let mut bytes = [0u8; 24];
+            let len = next() as usize % bytes.len();
+            for byte in bytes.iter_mut() {
+                *byte = next();
+            }
+            let bytes = &bytes[..len];
+            for byte in bytes {
+                if let Ok(Some(packet)) = parser.parse_byte(*byte) {
+                    let _ = reassembler.push(&packet);
+                    let _ = (
+                        packet.payload(),
+                        packet.verify_crc(),
+                        packet.split(),
+                        packet.payload_u32_le(1),
+                    );
+                }
+                let _ = strict.parse_byte_at(*byte, u32::from(*byte));
+                let _ = length_prefixed.parse_byte(*byte);
             }
+            let _ = ShprotoPacket::<16>::from_bytes(bytes);
+            let _ = ShprotoPacket::<16>::from_payload(bytes.first().copied().unwrap_or(0), bytes);
+            let _ = super::encode_into(0x03, bytes, &mut [0u8; 16]);
         }
-        assert_eq!(packet_counter, 1);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn crc16_slice() {
+        assert_eq!(super::crc16_slice(&[0x03, 0x99]), CRC_03_99);
+        assert_eq!(super::crc16_slice(&[]), 0xFFFF);
+        assert_eq!(super::crc16_slice(&FRAME[2..7]), 0);
+    }
+
+    #[cfg(feature = "capture-raw")]
+    #[test]
+    fn capture_raw() {
+        let stream = [0xFE, 0x03, 0xFD, 0x01, 0x00, 0x01, 0xA0, 0x50, 0xA5];
+        let mut parser = ShprotoParser::<256>::with_crc_params(CrcParams::MODBUS);
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        parser
+            .parse_slice(&[0xFF, 0x11, 0xFE, 0x03, 0x00], &mut out)
+            .unwrap();
+        parser.parse_slice(&stream, &mut out).unwrap();
+        assert_eq!(out[0].raw_bytes(), &stream);
+        assert!(out[0].is_valid());
+        assert_eq!(out[0].payload(), &[0x03, 0xFE, 0x00, 0x01]);
+        assert!(ShprotoPacket::<256>::from_payload(0x03, &[])
+            .unwrap()
+            .raw_bytes()
+            .is_empty());
+        let mut reused = out[0].clone();
+        reused.clear();
+        assert!(reused.raw_bytes().is_empty());
+    }
+
+    #[test]
+    fn from_command() {
+        #[derive(Copy, Clone)]
+        enum MyCmd {
+            Ping = 0x03,
+        }
+        impl Command for MyCmd {
+            fn byte(&self) -> u8 {
+                *self as u8
+            }
+        }
+        let packet = ShprotoPacket::<256>::from_command(MyCmd::Ping, &[0x00, 0x01]).unwrap();
+        assert_eq!(packet.as_bytes(), &FRAME);
+        assert_eq!(
+            ShprotoPacket::<256>::from_command(0x03u8, &[0x00, 0x01]).unwrap(),
+            packet
+        );
+    }
+
+    #[test]
+    fn parser_phase_sequence() {
+        use ParserPhase::*;
+        let frame = [0xFF, 0xFE, 0x03, 0xFD, 0x01, 0x00, 0x01, 0xA0, 0x50, 0xA5];
+        let expected = [
+            Start,
+            Data,
+            Data,
+            EscapedData,
+            Data,
+            Data,
+            Data,
+            Data,
+            Data,
+            Start,
+        ];
+        let mut parser = ShprotoParser::<256>::new();
+        let mut phases = heapless::Vec::<ParserPhase, 16>::new();
+        for byte in frame {
+            parser.parse_byte(byte).unwrap();
+            phases.push(parser.state()).unwrap();
+        }
+        assert_eq!(phases.as_slice(), &expected);
+    }
+
+    #[test]
+    fn escape_then_stop() {
+        let mut parser = ShprotoParser::<256>::new();
+        parser.set_report_aborted(true);
+        for byte in [0xFE, 0x03, 0xFD] {
+            assert_eq!(parser.parse_byte(byte), Ok(None));
+        }
+        assert_eq!(parser.parse_byte(0xA5), Err(ShprotoError::InvalidEscape));
+        assert!(parser.in_progress().is_none());
+        let packet = FRAME[1..]
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert_eq!(packet, [0x03, 0x00, 0x01].as_slice());
+    }
+
+    #[test]
+    fn remaining() {
+        let mut packet = ShprotoPacket::<16>::new();
+        assert_eq!(packet.remaining(), 14);
+        packet.start(0x03).unwrap();
+        assert_eq!(packet.remaining(), 13);
+        packet.add_byte(0xFE).unwrap();
+        assert_eq!(packet.remaining(), 11);
+        packet.add_byte(0x01).unwrap();
+        assert_eq!(packet.remaining(), 10);
+    }
+
+    #[test]
+    fn finish() {
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0x00, 0x01]).unwrap();
+        let frame = FRAME;
+        assert_eq!(packet.finish().unwrap(), &frame);
+        assert_eq!(packet.finish().unwrap(), &frame);
+        assert!(packet.is_valid());
+    }
+
+    #[test]
+    fn already_complete() {
+        let mut packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        let frame = packet.clone();
+        assert_eq!(packet.add_byte(0x02), Err(ShprotoError::AlreadyComplete));
+        assert_eq!(packet.start(0x04), Err(ShprotoError::AlreadyComplete));
+        assert_eq!(packet.complete(), Err(ShprotoError::AlreadyComplete));
+        assert_eq!(packet.as_bytes(), frame.as_bytes());
+        packet.clear();
+        packet.start(0x04).unwrap();
+    }
+
+    #[test]
+    fn crc16_accumulator() {
+        let mut crc = Crc16::new();
+        crc.update(0x03);
+        crc.update(0x99);
+        assert_eq!(crc.finalize(), CRC_03_99);
+        let mut crc = Crc16::default();
+        crc.update_slice(&[0x03, 0x99]);
+        assert_eq!(crc.finalize(), CRC_03_99);
+        let mut ccitt = Crc16::with_params(CrcParams::CCITT);
+        ccitt.update_slice(&[0x03, 0x99]);
+        assert_eq!(ccitt.finalize(), 0xD398);
+    }
+
+    #[test]
+    fn inter_frame_filler() {
+        let frame = FRAME;
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        parser.parse_slice(&frame, &mut out).unwrap();
+        parser.parse_slice(&[0xFF; 8], &mut out).unwrap();
+        parser.parse_slice(&frame, &mut out).unwrap();
+        parser.parse_slice(&[0xFF; 3], &mut out).unwrap();
+        assert_eq!(out.len(), 2);
+        assert!(out.iter().all(|p| p.is_valid()));
+        assert_eq!(parser.stats().bytes_dropped, 0);
+    }
+
+    #[test]
+    fn crc_bytes() {
+        let mut packet = ShprotoPacket::<256>::with_crc_params(CrcParams::MODBUS);
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0x70, 0x04]).unwrap();
+        assert_eq!(packet.crc_bytes(), None);
+        let crc = packet.crc.finalize();
+        packet.complete().unwrap();
+        assert_eq!(packet.crc_bytes(), Some(crc.to_le_bytes()));
+        assert_eq!(packet.crc_bytes(), Some([0xA5, 0xC3]));
+    }
+
+    #[test]
+    fn payload_owned() {
+        let payload = {
+            let packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+            packet.payload_owned::<16>().unwrap()
+        };
+        assert_eq!(payload.as_slice(), &[0x03, 0x00, 0x01]);
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        assert_eq!(
+            packet.payload_owned::<2>(),
+            Err(ShprotoError::BufferOverflow)
+        );
+    }
+
+    #[test]
+    fn required_buffer() {
+        assert_eq!(FRAME_OVERHEAD, 5);
+        assert_eq!(super::required_buffer(32), 71);
+        let packet = ShprotoPacket::<{ super::required_buffer(0) }>::new();
+        assert_eq!(packet.remaining(), 5);
+        let packet =
+            ShprotoPacket::<{ super::required_buffer(3) }>::from_payload(0x03, &[0x00, 0x01])
+                .unwrap();
+        assert_eq!(packet.as_bytes().len(), 3 + FRAME_OVERHEAD);
+
+        // worst case: every payload byte and both MODBUS CRC bytes escaped
+        let mut args = [0xFE; 31];
+        args[25..].copy_from_slice(&[0xFE, 0xA5, 0xFD, 0xA5, 0xA5, 0xFE]);
+        let packet = modbus_packet::<{ super::required_buffer(32) }>(0xFE, &args);
+        assert_eq!(packet.crc_bytes(), Some([0xA5, 0xFD]));
+        assert_eq!(packet.as_bytes().len(), super::required_buffer(32));
+    }
+
+    #[test]
+    fn parse_slice_with() {
+        let frame = FRAME;
+        let mut bytes = heapless::Vec::<u8, 16>::new();
+        bytes.extend_from_slice(&frame).unwrap();
+        bytes.extend_from_slice(&frame).unwrap();
+        let mut parser = ShprotoParser::<256>::new();
+        let mut count = 0;
+        parser
+            .parse_slice_with(&bytes, |packet| {
+                assert_eq!(*packet, [0x03, 0x00, 0x01].as_slice());
+                count += 1;
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+    }
+
+    #[test]
+    fn already_started() {
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        assert_eq!(packet.start(0x04), Err(ShprotoError::AlreadyStarted));
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.add_byte(0x00).unwrap();
+        assert_eq!(packet.start(0x03), Err(ShprotoError::AlreadyStarted));
+        assert_eq!(packet.as_bytes(), &[0xFF, 0xFE, 0x00]);
+    }
+
+    #[test]
+    fn without_crc() {
+        let mut packet = ShprotoPacket::<256>::without_crc();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0xFE, 0x01]).unwrap();
+        packet.complete().unwrap();
+        assert_eq!(
+            packet.as_bytes(),
+            &[0xFF, 0xFE, 0x03, 0xFD, 0x01, 0x01, 0xA5]
+        );
+        assert!(packet.is_valid() && packet.crc_bytes().is_none());
+
+        let mut parser = ShprotoParser::<256>::new();
+        parser.set_crc(false);
+        parser.set_strict(true);
+        let decoded = packet
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(decoded.is_valid());
+        assert_eq!(decoded, [0x03, 0xFE, 0x01].as_slice());
+        assert_eq!(decoded.as_bytes(), packet.as_bytes());
+    }
+
+    #[test]
+    fn frame_len() {
+        let mut packet = ShprotoPacket::<256>::new();
+        packet.start(0x03).unwrap();
+        packet.add_bytes(&[0x00, 0x01]).unwrap();
+        assert_eq!(packet.frame_len(), None);
+        packet.complete().unwrap();
+        assert_eq!(packet.frame_len(), Some(8));
+        assert_eq!(packet.len(), 3);
+        let escaped = ShprotoPacket::<256>::from_payload(0x03, &[0xFE]).unwrap();
+        assert_eq!(escaped.frame_len(), Some(escaped.as_bytes().len()));
+    }
+
+    #[test]
+    fn parse_until_packet() {
+        let mut stream = heapless::Vec::<u8, 16>::new();
+        stream.extend_from_slice(&FRAME).unwrap();
+        stream.extend_from_slice(&[0xFF, 0xFE, 0x03]).unwrap();
+        let mut parser = ShprotoParser::<256>::new();
+        let mut cursor = &stream[..4];
+        assert_eq!(parser.parse_until_packet(&mut cursor), Ok(None));
+        assert!(cursor.is_empty());
+        let mut cursor = &stream[4..];
+        let packet = parser.parse_until_packet(&mut cursor).unwrap().unwrap();
+        assert_eq!(packet, [0x03, 0x00, 0x01].as_slice());
+        assert_eq!(cursor, &[0xFF, 0xFE, 0x03]);
+    }
+
+    #[test]
+    fn offset_decoder() {
+        let frame = FRAME;
+        let mut bytes = heapless::Vec::<u8, 32>::new();
+        bytes.extend_from_slice(&frame).unwrap();
+        bytes.extend_from_slice(&[0x11, 0x22]).unwrap();
+        bytes.extend_from_slice(&frame).unwrap();
+        let mut decoder = OffsetDecoder::<256>::new(&bytes);
+        let (start, end, packet) = decoder.next().unwrap();
+        assert_eq!((start, end), (1, 8));
+        assert!(packet.unwrap().is_valid());
+        let (start, end, packet) = decoder.next().unwrap();
+        assert_eq!((start, end), (11, 18));
+        assert_eq!(&bytes[start..end], &frame[1..]);
+        assert!(packet.unwrap().is_valid());
+        assert!(decoder.next().is_none());
+
+        let mut parser = ShprotoParser::<256>::new();
+        parser.set_report_aborted(true);
+        let mut cut = heapless::Vec::<u8, 16>::new();
+        cut.extend_from_slice(&[0xFE, 0x03, 0x00]).unwrap();
+        cut.extend_from_slice(&FRAME[1..]).unwrap();
+        let spans = OffsetDecoder::with_parser(&cut, parser)
+            .map(|(start, end, _)| (start, end))
+            .collect::<heapless::Vec<_, 2>>();
+        assert_eq!(spans.as_slice(), &[(0, 3), (3, 10)]);
+    }
+
+    #[test]
+    fn push_chain() {
+        let mut packet = ShprotoPacket::<256>::new().push(0x03).push(0x99);
+        packet.complete().unwrap();
+        assert_eq!(
+            packet,
+            ShprotoPacket::<256>::from_payload(0x03, &[0x99]).unwrap()
+        );
+        assert!(packet.is_valid());
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    #[should_panic]
+    fn push_chain_overflow() {
+        let _ = ShprotoPacket::<6>::new()
+            .push(0x03)
+            .push(0x01)
+            .push(0x02)
+            .push(0x04)
+            .push(0x05);
+    }
+
+    #[test]
+    fn decode_as() {
+        #[derive(Debug, PartialEq)]
+        struct Reading {
+            channel: u8,
+            value: u8,
+        }
+        let decode = |command: u8, args: &[u8]| match (command, args) {
+            (0x03, [channel, value]) => Some(Reading {
+                channel: *channel,
+                value: *value,
+            }),
+            _ => None,
+        };
+        let packet = ShprotoPacket::<256>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        assert_eq!(
+            packet.decode_as(decode),
+            Some(Reading {
+                channel: 0x00,
+                value: 0x01
+            })
+        );
+        assert_eq!(
+            ShprotoPacket::<256>::heartbeat(0x03)
+                .unwrap()
+                .decode_as(decode),
+            None
+        );
+        let corrupt =
+            ShprotoPacket::<256>::from_bytes(&[0xFF, 0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5])
+                .unwrap();
+        assert_eq!(corrupt.decode_as(decode), None);
+    }
+
+    #[test]
+    fn back_to_back_frames() {
+        let frame = &FRAME[1..];
+        let mut bytes = heapless::Vec::<u8, 16>::new();
+        bytes.extend_from_slice(frame).unwrap();
+        bytes.extend_from_slice(frame).unwrap();
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 2>::new();
+        parser.parse_slice(&bytes, &mut out).unwrap();
+        assert_eq!(out.len(), 2);
+        assert!(out
+            .iter()
+            .all(|p| p.is_valid() && *p == [0x03, 0x00, 0x01].as_slice()));
+        assert_eq!(parser.stats().bytes_dropped, 0);
+    }
+
+    #[test]
+    fn encoded_len() {
+        let args = [0xFE, 0xFD, 0xA5, 0xFE];
+        let packet = ShprotoPacket::<32>::from_payload(0xFD, &args).unwrap();
+        assert_eq!(super::encoded_len(0xFD, &args), packet.as_bytes().len());
+        let packet = ShprotoPacket::<32>::from_payload(0x03, &[0x70, 0x04]).unwrap();
+        assert_eq!(
+            super::encoded_len(0x03, &[0x70, 0x04]),
+            packet.as_bytes().len()
+        );
+        // under MODBUS the CRC low byte 0xA5 is escaped too
+        let encoder = Encoder::with_crc_params(0x03, &[0x70, 0x04], CrcParams::MODBUS);
+        assert_eq!(encoder.count(), 9);
+    }
+
+    #[test]
+    fn validating_parser() {
+        assert!(core::mem::size_of::<ValidatingParser>() < 32);
+        let mut parser = ValidatingParser::<DefaultFraming>::new();
+        let good = ShprotoPacket::<32>::from_payload(0x03, &[0xFE, 0x00]).unwrap();
+        let summary = good
+            .as_bytes()
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(summary.is_valid());
+        assert_eq!(summary.command(), Some(0x03));
+        assert_eq!(summary.payload_len(), 3);
+
+        let corrupt = [0xFF, 0xFE, 0x03, 0x00, 0x02, 0x40, 0x00, 0xA5];
+        let summary = corrupt
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(!summary.is_valid());
+        assert_eq!(summary.command(), Some(0x03));
+        assert_eq!(parser.parse_byte(0xFE), Ok(None));
+        assert_eq!(parser.parse_byte(0xFD), Ok(None));
+        assert_eq!(parser.parse_byte(0x00), Err(ShprotoError::InvalidEscape));
+    }
+
+    #[test]
+    fn payload_eq() {
+        let mut parser = ShprotoParser::<256>::new();
+        let frame = FRAME;
+        let packet = frame
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert!(packet.payload_eq(0x03, &[0x00, 0x01]));
+        assert!(!packet.payload_eq(0x03, &[0x00]));
+        assert!(!packet.payload_eq(0x04, &[0x00, 0x01]));
+        let corrupt =
+            ShprotoPacket::<256>::from_bytes(&[0xFF, 0xFE, 0x03, 0x00, 0x01, 0x41, 0x00, 0xA5])
+                .unwrap();
+        assert!(corrupt.payload_eq(0x03, &[0x00, 0x01]) && !corrupt.is_valid());
+    }
+
+    #[test]
+    fn orphan_stops() {
+        let mut parser = ShprotoParser::<256>::new();
+        let mut out = heapless::Vec::<ShprotoPacket<256>, 1>::new();
+        parser
+            .parse_slice(&[0x00, 0x40, 0x00, 0xA5, 0x11], &mut out)
+            .unwrap();
+        parser.parse_slice(&FRAME[1..], &mut out).unwrap();
+        let stats = parser.stats();
+        assert_eq!(stats.orphan_stops, 1);
+        assert_eq!(stats.bytes_dropped, 5);
+        assert_eq!(stats.frames_decoded, 1);
+    }
+
+    #[test]
+    fn buffered_decoder_drain() {
+        let mut decoder = BufferedDecoder::<256, 4>::new();
+        for args in [[0x00, 0x01], [0x00, 0x02]] {
+            let packet = ShprotoPacket::<256>::from_payload(0x03, &args).unwrap();
+            for byte in packet.as_bytes() {
+                decoder.push(*byte).unwrap();
+            }
+        }
+        let drained = decoder.drain().collect::<heapless::Vec<_, 4>>();
+        assert_eq!(drained.len(), 2);
+        assert!(drained[0].payload_eq(0x03, &[0x00, 0x01]) && drained[0].is_valid());
+        assert!(drained[1].payload_eq(0x03, &[0x00, 0x02]) && drained[1].is_valid());
+        assert!(decoder.pop().is_none());
+    }
+
+    #[cfg(feature = "timestamp")]
+    #[test]
+    fn packet_timestamp() {
+        let frame = FRAME;
+        let mut parser = ShprotoParser::<256>::new();
+        let packet = frame
+            .iter()
+            .zip(100u32..)
+            .find_map(|(b, now)| parser.parse_byte_at(*b, now).unwrap())
+            .unwrap();
+        assert_eq!(packet.timestamp(), Some(107));
+        let packet = frame
+            .iter()
+            .find_map(|b| parser.parse_byte(*b).unwrap())
+            .unwrap();
+        assert_eq!(packet.timestamp(), None);
+        let mut stamped = frame
+            .iter()
+            .find_map(|b| parser.parse_byte_at(*b, 200).unwrap())
+            .unwrap();
+        stamped.clear();
+        stamped.start(0x03).unwrap();
+        stamped.complete().unwrap();
+        assert_eq!(stamped.timestamp(), None);
+    }
+
+    #[test]
+    fn raw_and_logical_counts() {
+        let packet = modbus_packet::<64>(0xFE, &[0xFD, 0x00, 0xA5, 0xFE]);
+        let frame = packet.as_bytes();
+        let mut parser = ShprotoParser::<64>::with_crc_params(CrcParams::MODBUS);
+        for byte in &frame[..frame.len() - 1] {
+            parser.parse_byte(*byte).unwrap();
+        }
+        let escapes = frame[2..].iter().filter(|b| **b == 0xFD).count();
+        assert_eq!(escapes, 4);
+        assert_eq!(parser.logical_byte_count(), 5 + 2);
+        assert_eq!(
+            parser.raw_byte_count() - parser.logical_byte_count(),
+            escapes
+        );
+        assert!(parser.parse_byte(0xA5).unwrap().unwrap().is_valid());
+        assert_eq!(parser.raw_byte_count(), frame.len() - 3);
+        parser.parse_byte(0xFE).unwrap();
+        assert_eq!(
+            (parser.raw_byte_count(), parser.logical_byte_count()),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn respond_to() {
+        let request = ShprotoPacket::<64>::from_payload(0x03, &[0x00, 0x01]).unwrap();
+        let response = ShprotoPacket::respond_to(&request, &[0x2A, 0xFE]).unwrap();
+        assert_eq!(response.command(), Some(0x03));
+        assert_eq!(response.payload(), &[0x03, 0x2A, 0xFE]);
+        assert!(response.is_complete() && response.is_valid());
+
+        let empty = ShprotoPacket::<64>::new();
+        assert_eq!(
+            ShprotoPacket::respond_to(&empty, &[]).unwrap_err(),
+            ShprotoError::MalformedFrame
+        );
+    }
+
+    #[test]
+    fn address() {
+        let packet = ShprotoPacket::<64>::with_address(0x12, 0x03, &[0x00, 0xFE]).unwrap();
+        assert_eq!(packet.command(), Some(0x03));
+        assert_eq!(packet.split(), Some((0x03, &[0x00, 0xFE][..])));
+        let mut parser = ShprotoParser::<64>::new();
+        parser.set_addressed(true);
+        let mut input = packet.as_bytes();
+        let parsed = parser.parse_until_packet(&mut input).unwrap().unwrap();
+        assert!(parsed.is_valid());
+        assert_eq!(parsed.address(), Some(0x12));
+        assert_eq!(parsed.command(), Some(0x03));
+        assert_eq!(parsed.payload(), &[0x03, 0x00, 0xFE]);
+        assert_eq!(parsed, packet);
+        let plain = ShprotoPacket::<64>::from_payload(0x03, &[0x00]).unwrap();
+        assert_eq!(plain.address(), None);
+        let response = ShprotoPacket::respond_to(&parsed, &[0x01]).unwrap();
+        assert_eq!(response.address(), Some(0x12));
+        assert_eq!(response.split(), Some((0x03, &[0x01][..])));
+    }
+
+    #[test]
+    fn address_eq() {
+        let addressed = ShprotoPacket::<64>::with_address(0x12, 0x03, &[]).unwrap();
+        let plain = ShprotoPacket::<64>::from_payload(0x12, &[0x03]).unwrap();
+        assert_eq!(addressed.as_bytes(), plain.as_bytes());
+        assert_ne!(addressed, plain);
+        assert_ne!(addressed, [0x12, 0x03].as_slice());
+        assert_eq!(addressed, [0x03].as_slice());
+    }
+
+    #[cfg(all(feature = "serde", feature = "std"))]
+    #[test]
+    fn address_serde() {
+        let packet = ShprotoPacket::<64>::with_address(0x12, 0x03, &[0x00]).unwrap();
+        let json = serde_json::to_string(&packet).unwrap();
+        assert!(json.ends_with(r#""address":18}"#));
+        let decoded: ShprotoPacket<64> = serde_json::from_str(&json).unwrap();
+        assert_eq!(decoded.as_bytes(), packet.as_bytes());
+        assert_eq!(decoded, packet);
+    }
+
+    #[test]
+    fn address_clear() {
+        let mut packet = ShprotoPacket::<64>::with_address(0x12, 0x03, &[]).unwrap();
+        packet.clear();
+        packet.start(0x04).unwrap();
+        packet.add_byte(0x01).unwrap();
+        packet.complete().unwrap();
+        assert_eq!(packet.address(), None);
+        assert_eq!(packet.command(), Some(0x04));
+        assert_eq!(
+            packet,
+            ShprotoPacket::<64>::from_payload(0x04, &[0x01]).unwrap()
+        );
+    }
+
+    #[test]
+    fn is_for() {
+        let direct = ShprotoPacket::<64>::with_address(0x12, 0x03, &[]).unwrap();
+        assert!(direct.is_for(0x12));
+        assert!(!direct.is_for(0x13));
+        let broadcast = ShprotoPacket::<64>::with_address(Address::BROADCAST, 0x03, &[]).unwrap();
+        assert!(broadcast.is_for(0x12));
+        assert!(broadcast.is_for(0x13));
+        let unaddressed = ShprotoPacket::<64>::from_payload(0xFF, &[]).unwrap();
+        assert!(!unaddressed.is_for(0x12));
+        assert!(!ShprotoPacket::<64>::new().is_for(0x12));
+    }
+}